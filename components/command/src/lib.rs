@@ -0,0 +1,41 @@
+use bindings::wasi::cli0_2_0::{environment, exit, stderr, stdin, stdout};
+
+struct Component;
+
+helper::gen_command_bindings!(Component);
+
+impl bindings::Guest for Component {
+    fn run() -> Result<(), ()> {
+        let mut input = Vec::new();
+        let stdin = stdin::get_stdin();
+        while let Ok(bytes) = stdin.blocking_read(4096) {
+            input.extend(bytes);
+        }
+
+        // The first argument is the name of the program
+        let args = environment::get_arguments()
+            .into_iter()
+            .skip(1)
+            .collect::<Vec<_>>();
+        let stdout = stdout::get_stdout();
+        let output = format!(
+            "args: {}\nstdin: {}\n",
+            args.join(" "),
+            String::from_utf8_lossy(&input)
+        );
+        stdout.blocking_write_and_flush(output.as_bytes()).unwrap();
+
+        let env = environment::get_environment();
+        let greeting = env
+            .iter()
+            .find(|(k, _)| k == "GREETING")
+            .map(|(_, v)| v.as_str());
+        let stderr = stderr::get_stderr();
+        let output = format!("GREETING={}\n", greeting.unwrap_or_default());
+        stderr.blocking_write_and_flush(output.as_bytes()).unwrap();
+
+        let failed = env.iter().any(|(k, _)| k == "EXIT_WITH_ERROR");
+        exit::exit(if failed { Err(()) } else { Ok(()) });
+        Ok(())
+    }
+}
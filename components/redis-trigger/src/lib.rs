@@ -0,0 +1,25 @@
+use helper::bindings::fermyon::spin2_0_0::{redis, variables};
+
+struct Component;
+
+helper::gen_redis_trigger_bindings!(Component);
+
+/// The key the received message is written to
+const MESSAGE_KEY: &str = "redis-trigger-message";
+
+impl bindings::Guest for Component {
+    fn handle_message(message: bindings::Payload) -> Result<(), bindings::Error> {
+        handle(message).map_err(|e| {
+            eprintln!("failed to handle message: {e}");
+            bindings::Error::Error
+        })
+    }
+}
+
+/// Write the message to a key so that the test can observe it was received
+fn handle(message: Vec<u8>) -> anyhow::Result<()> {
+    let address = variables::get("redis_address")?;
+    let connection = redis::Connection::open(&address)?;
+    connection.set(MESSAGE_KEY, &message)?;
+    Ok(())
+}
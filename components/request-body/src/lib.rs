@@ -0,0 +1,38 @@
+use helper::bindings::wasi::{
+    http0_2_0::types::{Fields, IncomingRequest, OutgoingBody, OutgoingResponse, ResponseOutparam},
+    io0_2_0::streams::StreamError,
+};
+
+pub struct Component;
+helper::gen_http_trigger_bindings!(Component);
+
+impl bindings::Guest for Component {
+    fn handle(request: IncomingRequest, response_out: ResponseOutparam) {
+        let incoming_body = request.consume().unwrap();
+        let incoming_stream = incoming_body.stream().unwrap();
+
+        // Without a `content-length` header the response is streamed back as it's read
+        let response = OutgoingResponse::new(Fields::new());
+        response.set_status_code(200).unwrap();
+        let outgoing_body = response.body().unwrap();
+        {
+            let outgoing_stream = outgoing_body.write().unwrap();
+            ResponseOutparam::set(response_out, Ok(response));
+
+            loop {
+                match incoming_stream.blocking_read(1024) {
+                    Ok(buffer) => {
+                        outgoing_stream.blocking_write_and_flush(&buffer).unwrap();
+                    }
+                    Err(StreamError::Closed) => break,
+                    Err(StreamError::LastOperationFailed(error)) => {
+                        panic!("{}", error.to_debug_string())
+                    }
+                }
+            }
+            // The outgoing stream must be dropped before the outgoing body is finished.
+        }
+
+        OutgoingBody::finish(outgoing_body, None).unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+use helper::bindings::wasi::http0_2_0::types::{
+    Fields, IncomingRequest, Method, OutgoingResponse, ResponseOutparam,
+};
+
+pub struct Component;
+helper::gen_http_trigger_bindings!(Component);
+
+impl bindings::Guest for Component {
+    fn handle(request: IncomingRequest, response_out: ResponseOutparam) {
+        helper::handle_result(handle(request), response_out);
+    }
+}
+
+/// Respond with the method of the request in both the `x-method` header and the body
+///
+/// The header allows checking the method of `HEAD` requests whose responses have no body.
+fn handle(req: IncomingRequest) -> anyhow::Result<OutgoingResponse> {
+    let method = method_name(req.method());
+    let headers = Fields::from_list(&[("x-method".into(), method.clone().into_bytes())])?;
+    let response = OutgoingResponse::new(headers);
+    response.set_status_code(200).unwrap();
+    helper::write_outgoing_body(response.body().unwrap(), method.as_bytes());
+    Ok(response)
+}
+
+fn method_name(method: Method) -> String {
+    match method {
+        Method::Get => "GET".into(),
+        Method::Head => "HEAD".into(),
+        Method::Post => "POST".into(),
+        Method::Put => "PUT".into(),
+        Method::Delete => "DELETE".into(),
+        Method::Connect => "CONNECT".into(),
+        Method::Options => "OPTIONS".into(),
+        Method::Trace => "TRACE".into(),
+        Method::Patch => "PATCH".into(),
+        Method::Other(method) => method,
+    }
+}
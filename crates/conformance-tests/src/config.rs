@@ -1,10 +1,38 @@
 use anyhow::Context as _;
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
 
 /// Parse the test configuration from a file
+///
+/// Paths referenced by the config are resolved relative to the file's directory.
 pub fn parse_from_file(path: impl AsRef<Path>) -> anyhow::Result<TestConfig> {
+    let path = path.as_ref();
     let config = std::fs::read_to_string(path).context("failed to read test manifest")?;
-    parse(&config)
+    let mut config = parse(&config)?;
+    if let Some(test_dir) = path.parent() {
+        config.resolve_paths(test_dir)?;
+    }
+    Ok(config)
+}
+
+/// Resolve a path relative to the test's directory, ensuring it stays inside of it
+///
+/// Symlinks are followed so that neither `..` nor a link can point outside of the test.
+pub fn confine(test_dir: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let canonical_dir = test_dir
+        .canonicalize()
+        .with_context(|| format!("failed to resolve test directory {test_dir:?}"))?;
+    let resolved = test_dir
+        .join(path)
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {path:?} in test directory {test_dir:?}"))?;
+    anyhow::ensure!(
+        resolved.starts_with(&canonical_dir),
+        "{path:?} is outside of the test directory {test_dir:?}"
+    );
+    Ok(resolved)
 }
 
 /// Parse the test configuration
@@ -16,15 +44,169 @@ pub fn parse(config: &str) -> anyhow::Result<TestConfig> {
 #[derive(Debug, Clone, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct TestConfig {
+    /// A human readable description of what the test covers
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Free form tags for categorizing tests
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The capabilities a runtime must support for the test to apply to it
+    #[serde(default)]
+    pub requires: Vec<Capability>,
+    /// The earliest version of Spin the test applies to
+    #[serde(default, rename = "min-spin-version")]
+    pub min_spin_version: Option<semver::Version>,
+    /// The runtime is expected to refuse to start the app instead of running the invocations
+    #[serde(default, rename = "startup-failure")]
+    pub startup_failure: Option<StartupFailure>,
+    #[serde(default)]
     pub invocations: Vec<Invocation>,
     #[serde(default)]
     pub preconditions: Vec<Precondition>,
+    /// Conditions on the state of the services checked after all invocations have run
+    #[serde(default)]
+    pub postconditions: Vec<Postcondition>,
+}
+
+impl TestConfig {
+    /// Make all paths referenced by the test config relative to the test's directory
+    ///
+    /// Errors if a referenced file doesn't exist or is outside of the test's directory.
+    pub fn resolve_paths(&mut self, test_dir: &Path) -> anyhow::Result<()> {
+        let mut matchers = Vec::new();
+        if let Some(startup_failure) = &mut self.startup_failure {
+            matchers.push(&mut startup_failure.output);
+        }
+        for invocation in &mut self.invocations {
+            match invocation {
+                Invocation::Http(invocation) => matchers.push(&mut invocation.response.body),
+                Invocation::Command(invocation) => {
+                    matchers.push(&mut invocation.output.stdout);
+                    matchers.push(&mut invocation.output.stderr);
+                }
+                Invocation::Redis(_) => {}
+            }
+        }
+        for postcondition in &mut self.postconditions {
+            if let Postcondition::OutboundHttp { requests } = postcondition {
+                matchers.extend(requests.iter_mut().map(|r| &mut r.body));
+            }
+        }
+        for matcher in matchers {
+            if let Some(BodyMatcher::File(path)) = matcher {
+                *path = confine(test_dir, path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The services that must be running to satisfy the test's preconditions
+    pub fn services_config(&self) -> anyhow::Result<test_environment::services::ServicesConfig> {
+        let services = self
+            .preconditions
+            .iter()
+            .filter_map(Precondition::service_name)
+            .collect::<Vec<_>>();
+        test_environment::services::ServicesConfig::new(services)
+    }
+}
+
+/// A feature or interface a runtime supports (e.g. `spin:postgres@4.0.0` or `wasi:http@0.2.0`)
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Capability {
+    pub name: String,
+    pub version: Option<semver::Version>,
+}
+
+impl Capability {
+    /// Whether a runtime supporting `self` satisfies a test requiring `required`
+    ///
+    /// A requirement without a version is satisfied by any version, otherwise the supported
+    /// version must be semver compatible with and no older than the required one.
+    pub fn satisfies(&self, required: &Capability) -> bool {
+        if self.name != required.name {
+            return false;
+        }
+        match (&self.version, &required.version) {
+            (_, None) => true,
+            (None, Some(_)) => false,
+            (Some(supported), Some(required)) => semver::VersionReq::parse(&format!("^{required}"))
+                .map(|req| req.matches(supported))
+                .unwrap_or(false),
+        }
+    }
+}
+
+impl std::str::FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, version) = match s.split_once('@') {
+            Some((name, version)) => {
+                let version = version
+                    .parse()
+                    .with_context(|| format!("invalid version in capability '{s}'"))?;
+                (name, Some(version))
+            }
+            None => (s, None),
+        };
+        anyhow::ensure!(!name.is_empty(), "capability '{s}' has no name");
+        Ok(Self {
+            name: name.to_owned(),
+            version,
+        })
+    }
+}
+
+impl TryFrom<String> for Capability {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}@{version}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// An expectation that the runtime refuses to start the app (e.g. because its manifest is invalid)
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StartupFailure {
+    /// The expected error output of the runtime
+    ///
+    /// A plain string is shorthand for [`BodyMatcher::Exact`]. If not present, the output is not checked.
+    #[serde(default, deserialize_with = "deserialize_body_matcher")]
+    pub output: Option<BodyMatcher>,
+}
+
+impl StartupFailure {
+    /// Assert that the runtime failed to start with the expected error output
+    pub fn assert(&self, runtime: &mut impl test_environment::Runtime) -> anyhow::Result<()> {
+        let output = runtime
+            .startup_failure()
+            .context("runtime started but was expected to fail to start")?;
+        if let Some(matcher) = &self.output {
+            crate::assertions::assert_body(matcher, output.as_bytes())
+                .context("unexpected error output from runtime")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
 #[serde(untagged)]
 pub enum Invocation {
     Http(HttpInvocation),
+    Redis(RedisInvocation),
+    Command(CommandInvocation),
 }
 
 /// An invocation of the runtime
@@ -32,22 +214,237 @@ pub enum Invocation {
 pub struct HttpInvocation {
     pub request: Request,
     pub response: Response,
+    /// Values to capture from the response into variables by name
+    ///
+    /// Captured variables can be used in the requests of later invocations with `%{var=name}`.
+    #[serde(default)]
+    pub capture: BTreeMap<String, Capture>,
 }
 
+/// Variables captured from the responses of earlier invocations by name
+pub type Variables = HashMap<String, String>;
+
 impl HttpInvocation {
     /// Run the invocation by sending the request and asserting the response
     pub fn run<F>(self, send: F) -> anyhow::Result<test_environment::http::Response>
     where
         F: for<'a, 'b> FnOnce(
-            test_environment::http::Request<'a, String>,
+            test_environment::http::Request<'a, test_environment::http::Body>,
+        ) -> anyhow::Result<test_environment::http::Response>,
+    {
+        self.run_with_variables(&mut Variables::new(), send)
+    }
+
+    /// Run the invocation with the variables captured by earlier invocations
+    ///
+    /// The variables are substituted into the request and the values captured from the response
+    /// are added to them.
+    pub fn run_with_variables<F>(
+        mut self,
+        variables: &mut Variables,
+        send: F,
+    ) -> anyhow::Result<test_environment::http::Response>
+    where
+        F: for<'a, 'b> FnOnce(
+            test_environment::http::Request<'a, test_environment::http::Body>,
         ) -> anyhow::Result<test_environment::http::Response>,
     {
-        self.request.send(|request| {
+        self.request.substitute_variables(variables)?;
+        let response = self.request.send(|request| {
             let response = send(request).context("failed to send the request to the runtime")?;
             crate::assertions::assert_response(&self.response, &response)
                 .context("assertion failed")?;
             Ok(response)
-        })
+        })?;
+        for (name, capture) in self.capture {
+            let value = capture
+                .extract(&response)
+                .with_context(|| format!("failed to capture variable '{name}'"))?;
+            variables.insert(name, value);
+        }
+        Ok(response)
+    }
+}
+
+/// An invocation of the runtime through a message published to a Redis channel
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisInvocation {
+    /// The channel the message is published to
+    pub channel: String,
+    /// The message to publish
+    pub message: String,
+    /// The effect handling the message must have
+    pub effect: RedisEffect,
+}
+
+/// How long to wait for effects of the runtime that happen asynchronously (e.g. handling a
+/// Redis message or publishing an MQTT message)
+const EFFECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Retry `check` until it succeeds or [`EFFECT_TIMEOUT`] has passed since `start`
+fn eventually(
+    start: std::time::Instant,
+    mut check: impl FnMut() -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    loop {
+        match check() {
+            Ok(()) => return Ok(()),
+            Err(e) if start.elapsed() > EFFECT_TIMEOUT => {
+                return Err(e.context(format!("not satisfied within {EFFECT_TIMEOUT:?}")))
+            }
+            Err(_) => std::thread::sleep(std::time::Duration::from_millis(100)),
+        }
+    }
+}
+
+impl RedisInvocation {
+    /// Run the invocation by publishing the message to the `redis` service and waiting for the
+    /// effect of the runtime handling it
+    pub fn run<R>(self, env: &mut test_environment::TestEnvironment<R>) -> anyhow::Result<()> {
+        let port = env
+            .get_service_port("redis", 6379)?
+            .context("redis service does not expose port 6379")?;
+        let mut connection =
+            crate::redis::Connection::open(test_environment::services::HOST, port)?;
+        let start = std::time::Instant::now();
+        // The runtime may not have subscribed to the channel yet
+        eventually(start, || {
+            let receivers = connection.publish(&self.channel, self.message.as_bytes())?;
+            anyhow::ensure!(
+                receivers > 0,
+                "no subscriber received the message published to channel '{}'",
+                self.channel
+            );
+            Ok(())
+        })?;
+        eventually(start, || self.effect.check(&mut connection))
+            .context("message was not handled as expected")
+    }
+}
+
+/// An observable effect of the runtime handling a Redis message
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum RedisEffect {
+    /// The key must have been set to the value in the `redis` service
+    Key { name: String, value: String },
+}
+
+impl RedisEffect {
+    fn check(&self, connection: &mut crate::redis::Connection) -> anyhow::Result<()> {
+        match self {
+            RedisEffect::Key { name, value } => {
+                let actual = connection
+                    .get(name)?
+                    .with_context(|| format!("key '{name}' is not set"))?;
+                anyhow::ensure!(
+                    actual == value.as_bytes(),
+                    "key '{name}' has unexpected value '{}' != '{value}'",
+                    String::from_utf8_lossy(&actual)
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An invocation of a command app through `wasi:cli/run`
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandInvocation {
+    pub command: Command,
+    /// The output required for the test to pass
+    pub output: ExpectedOutput,
+}
+
+impl CommandInvocation {
+    /// Run the invocation by running the command and asserting its output
+    pub fn run<F>(self, execute: F) -> anyhow::Result<CommandOutput>
+    where
+        F: FnOnce(&Command) -> anyhow::Result<CommandOutput>,
+    {
+        let output = execute(&self.command).context("failed to run the command")?;
+        crate::assertions::assert_output(&self.output, &output).context("assertion failed")?;
+        Ok(output)
+    }
+}
+
+/// How a command app is run
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Command {
+    /// The arguments passed to the app (not including the program name)
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The environment variables set for the app
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// What is written to the app's stdin
+    #[serde(default)]
+    pub stdin: Option<String>,
+}
+
+/// The output a command app must produce
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectedOutput {
+    #[serde(default, rename = "exit-code")]
+    pub exit_code: i32,
+    /// The expected stdout
+    ///
+    /// A plain string is shorthand for [`BodyMatcher::Exact`]. If not present, stdout is not checked.
+    #[serde(default, deserialize_with = "deserialize_body_matcher")]
+    pub stdout: Option<BodyMatcher>,
+    /// The expected stderr
+    ///
+    /// A plain string is shorthand for [`BodyMatcher::Exact`]. If not present, stderr is not checked.
+    #[serde(default, deserialize_with = "deserialize_body_matcher")]
+    pub stderr: Option<BodyMatcher>,
+}
+
+/// The output of a command app that was run
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub exit_code: i32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Where a captured value is taken from in the response
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capture {
+    /// The status code
+    Status,
+    /// The (first) value of the header with this name
+    Header(String),
+    /// The value at this JSON pointer (e.g. `/id`) in the JSON body
+    ///
+    /// Strings are captured without quotes while other values are captured as JSON.
+    Json(String),
+}
+
+impl Capture {
+    /// Extract the captured value from the response
+    pub fn extract(&self, response: &test_environment::http::Response) -> anyhow::Result<String> {
+        match self {
+            Capture::Status => Ok(response.status().to_string()),
+            Capture::Header(name) => response
+                .header_values(name)
+                .next()
+                .map(ToOwned::to_owned)
+                .with_context(|| format!("header '{name}' not found in response")),
+            Capture::Json(pointer) => {
+                let body: serde_json::Value =
+                    serde_json::from_slice(&response.body()).context("body is not valid JSON")?;
+                match body.pointer(pointer) {
+                    Some(serde_json::Value::String(value)) => Ok(value.clone()),
+                    Some(value) => Ok(value.to_string()),
+                    None => anyhow::bail!("no value at '{pointer}' in the JSON body"),
+                }
+            }
+        }
     }
 }
 
@@ -59,39 +456,119 @@ pub struct Request {
     #[serde(default)]
     pub headers: Vec<RequestHeader>,
     #[serde(default)]
-    pub body: Option<String>,
+    pub body: Option<HttpBody>,
+}
+
+/// The body of a request
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+pub enum HttpBody {
+    /// A UTF-8 string
+    Text(String),
+    /// Raw bytes
+    Bytes(Vec<u8>),
+    /// Chunks of bytes which are sent as a streaming upload
+    Chunks(Vec<Vec<u8>>),
 }
 
+impl From<HttpBody> for test_environment::http::Body {
+    fn from(body: HttpBody) -> Self {
+        match body {
+            HttpBody::Text(text) => Self::Full(text.into_bytes()),
+            HttpBody::Bytes(bytes) => Self::Full(bytes),
+            HttpBody::Chunks(chunks) => Self::Chunked(chunks),
+        }
+    }
+}
+
+/// The characters percent-encoded in values substituted into a request's path
+///
+/// Everything but the unreserved characters is encoded so a value stays a single path segment.
+const PATH_VALUE: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
 impl Request {
     /// Substitute template variables in the request with well known env variables
     ///
     /// Supported variables:
     /// - port: map a well known guest port to the port exposed by the service on the host
+    /// - service-port: map a guest port of a specific service (e.g. `redis:6379`) to its port on the host
+    /// - host: the host services are reachable at
+    /// - env: the value of an environment variable of the test environment or the test runner
+    ///
+    /// `var` templates are left in place to be substituted by [`Request::substitute_variables`].
     pub fn substitute_from_env<R>(
         &mut self,
         env: &mut test_environment::TestEnvironment<R>,
     ) -> anyhow::Result<()> {
         self.substitute(move |key, value| {
-            if key != "port" {
-                anyhow::bail!("unknown template key: {key}")
-            }
-            let port = env
-                .get_port(value.parse().context("port must be a number")?)?
-                .with_context(|| format!("no port {value} exposed by any service"))?;
-            Ok(Some(port.to_string()))
+            let replacement = match key {
+                "port" => env
+                    .get_port(value.parse().context("port must be a number")?)?
+                    .with_context(|| format!("no port {value} exposed by any service"))?
+                    .to_string(),
+                "service-port" => {
+                    let (name, guest_port) = value
+                        .split_once(':')
+                        .context("service-port must be in the form `name:port`")?;
+                    env.get_service_port(
+                        name,
+                        guest_port.parse().context("port must be a number")?,
+                    )?
+                    .with_context(|| format!("no port {guest_port} exposed by service '{name}'"))?
+                    .to_string()
+                }
+                "host" => test_environment::services::HOST.to_owned(),
+                "env" => match env.env_vars().get(value) {
+                    Some(var) => var.clone(),
+                    None => std::env::var(value)
+                        .with_context(|| format!("environment variable '{value}' is not set"))?,
+                },
+                // Variables are substituted with `Request::substitute_variables`
+                "var" => return Ok(None),
+                _ => anyhow::bail!("unknown template key: {key}"),
+            };
+            Ok(Some(replacement))
         })
     }
 
+    /// Substitute `%{var=name}` templates in the request with captured variables
+    ///
+    /// Values substituted into the path are percent-encoded.
+    pub fn substitute_variables(&mut self, variables: &Variables) -> anyhow::Result<()> {
+        let variable = |key: &str, name: &str| {
+            if key != "var" {
+                return Ok(None);
+            }
+            let value = variables
+                .get(name)
+                .with_context(|| format!("no variable '{name}' was captured"))?;
+            Ok(Some(value.clone()))
+        };
+        test_environment::manifest_template::replace_template(&mut self.path, |key, name| {
+            Ok(variable(key, name)?
+                .map(|value| percent_encoding::utf8_percent_encode(&value, PATH_VALUE).to_string()))
+        })?;
+        self.substitute(variable)
+    }
+
     /// Substitute template variables in the request
+    ///
+    /// Templates are substituted in the path, header values and a string body.
     pub fn substitute(
         &mut self,
         mut replacement: impl FnMut(&str, &str) -> anyhow::Result<Option<String>>,
     ) -> anyhow::Result<()> {
+        use test_environment::manifest_template::replace_template;
+        replace_template(&mut self.path, &mut replacement)?;
         for header in &mut self.headers {
-            test_environment::manifest_template::replace_template(
-                &mut header.value,
-                &mut replacement,
-            )?;
+            replace_template(&mut header.value, &mut replacement)?;
+        }
+        if let Some(HttpBody::Text(body)) = &mut self.body {
+            replace_template(body, &mut replacement)?;
         }
         Ok(())
     }
@@ -100,7 +577,7 @@ impl Request {
     pub fn send<F>(self, send: F) -> anyhow::Result<test_environment::http::Response>
     where
         F: for<'a, 'b> FnOnce(
-            test_environment::http::Request<'a, String>,
+            test_environment::http::Request<'a, test_environment::http::Body>,
         ) -> anyhow::Result<test_environment::http::Response>,
     {
         let headers = self
@@ -109,13 +586,10 @@ impl Request {
             .map(|h| (h.name.as_str(), h.value.as_str()))
             .collect::<Vec<_>>();
         let request = test_environment::http::Request::full(
-            match self.method {
-                Method::GET => test_environment::http::Method::Get,
-                Method::POST => test_environment::http::Method::Post,
-            },
+            self.method.into(),
             &self.path,
             &headers,
-            self.body,
+            self.body.map(Into::into),
         );
         send(request)
     }
@@ -126,7 +600,91 @@ pub struct Response {
     #[serde(default = "default_response_status")]
     pub status: u16,
     pub headers: Vec<ResponseHeader>,
-    pub body: Option<String>,
+    /// The expected body
+    ///
+    /// A plain string is shorthand for [`BodyMatcher::Exact`] and a list of bytes (or a list of
+    /// lists of bytes) for [`BodyMatcher::Bytes`]. If not present, the body must be empty.
+    #[serde(default, deserialize_with = "deserialize_body_matcher")]
+    pub body: Option<BodyMatcher>,
+    /// Whether headers not listed in `headers` are allowed in the response
+    #[serde(default, rename = "allow-extra-headers")]
+    pub allow_extra_headers: bool,
+}
+
+/// How the body of a response is matched
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum BodyMatcher {
+    /// The body must be exactly this UTF-8 string
+    Exact(String),
+    /// The body must be UTF-8 and match this regular expression
+    Regex(String),
+    /// The body must be UTF-8 and contain this string
+    Contains(String),
+    /// The body must be JSON structurally equal to `value`
+    ///
+    /// Object keys may be in any order. Values at the JSON pointers (e.g. `/id`) listed in
+    /// `ignore-paths` are not compared.
+    Json {
+        value: serde_json::Value,
+        #[serde(default, rename = "ignore-paths")]
+        ignore_paths: Vec<String>,
+    },
+    /// The body must be exactly these bytes
+    Bytes(Vec<u8>),
+    /// The body must be exactly these chunks concatenated and be sent with chunked transfer encoding
+    ///
+    /// If `exact_boundaries` is set, the body must also have been received in exactly these chunks.
+    #[serde(skip_deserializing)]
+    Chunks {
+        chunks: Vec<Vec<u8>>,
+        exact_boundaries: bool,
+    },
+    /// The body must be exactly these base64 encoded bytes
+    Base64(String),
+    /// The body must be exactly the contents of this file
+    ///
+    /// Relative paths are relative to the test's directory.
+    File(PathBuf),
+}
+
+fn deserialize_body_matcher<'de, D>(deserializer: D) -> Result<Option<BodyMatcher>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Exact(String),
+        Bytes(Vec<u8>),
+        Chunks(Vec<Vec<u8>>),
+        ChunksMatcher(ChunksMatcher),
+        Matcher(BodyMatcher),
+    }
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "kebab-case", deny_unknown_fields)]
+    struct ChunksMatcher {
+        chunks: Vec<Vec<u8>>,
+        #[serde(default)]
+        exact_boundaries: bool,
+    }
+    let repr: Option<Repr> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(repr.map(|repr| match repr {
+        Repr::Exact(body) => BodyMatcher::Exact(body),
+        Repr::Bytes(body) => BodyMatcher::Bytes(body),
+        Repr::Chunks(chunks) => BodyMatcher::Chunks {
+            chunks,
+            exact_boundaries: false,
+        },
+        Repr::ChunksMatcher(ChunksMatcher {
+            chunks,
+            exact_boundaries,
+        }) => BodyMatcher::Chunks {
+            chunks,
+            exact_boundaries,
+        },
+        Repr::Matcher(matcher) => matcher,
+    }))
 }
 
 fn default_response_status() -> u16 {
@@ -142,16 +700,278 @@ pub struct RequestHeader {
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct ResponseHeader {
     pub name: String,
+    /// The header must have exactly this one value (compared case-insensitively)
     pub value: Option<String>,
+    /// The header must have exactly these values in this order (compared case-insensitively)
+    pub values: Option<Vec<String>>,
+    /// Every value of the header must match this regular expression
+    pub regex: Option<String>,
     #[serde(default)]
     pub optional: bool,
 }
 
-#[derive(Debug, Clone, serde::Deserialize, Default)]
+/// The method of a request
+///
+/// Standard methods are matched case-insensitively while any other name is sent as is as an
+/// extension method.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, Default)]
+#[serde(from = "String")]
 pub enum Method {
     #[default]
     GET,
+    HEAD,
     POST,
+    PUT,
+    PATCH,
+    DELETE,
+    OPTIONS,
+    TRACE,
+    CONNECT,
+    Custom(String),
+}
+
+impl From<String> for Method {
+    fn from(method: String) -> Self {
+        match method.to_ascii_uppercase().as_str() {
+            "GET" => Method::GET,
+            "HEAD" => Method::HEAD,
+            "POST" => Method::POST,
+            "PUT" => Method::PUT,
+            "PATCH" => Method::PATCH,
+            "DELETE" => Method::DELETE,
+            "OPTIONS" => Method::OPTIONS,
+            "TRACE" => Method::TRACE,
+            "CONNECT" => Method::CONNECT,
+            _ => Method::Custom(method),
+        }
+    }
+}
+
+impl From<Method> for test_environment::http::Method {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::GET => Self::Get,
+            Method::HEAD => Self::Head,
+            Method::POST => Self::Post,
+            Method::PUT => Self::Put,
+            Method::PATCH => Self::Patch,
+            Method::DELETE => Self::Delete,
+            Method::OPTIONS => Self::Options,
+            Method::TRACE => Self::Trace,
+            Method::CONNECT => Self::Connect,
+            Method::Custom(method) => Self::Custom(method),
+        }
+    }
+}
+
+/// A condition on the state of the services that must hold once all invocations have run
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Postcondition {
+    /// The key must have the value in the `redis` service, or not exist if `value` is `None`
+    Redis { key: String, value: Option<String> },
+    /// Exactly these messages must have been published to the topic on the `mqtt` service
+    Mqtt {
+        topic: String,
+        messages: Vec<String>,
+    },
+    /// The query against the `postgres` service must return exactly these rows
+    ///
+    /// Values are compared as text with `NULL` being an empty string. The query is run as `user`
+    /// against `database`, which default to those of the `postgres` service.
+    Postgres {
+        query: String,
+        rows: Vec<Vec<String>>,
+        #[serde(default = "default_postgres_user")]
+        user: String,
+        #[serde(default = "default_postgres_database")]
+        database: String,
+    },
+    /// The `http-echo` service must have received requests with exactly these bodies
+    HttpEcho { received: Vec<String> },
+    /// The `tcp-echo` service must have received exactly this data on each connection
+    TcpEcho { received: Vec<String> },
+    /// The `http-echo` service must have received exactly these requests in this order
+    OutboundHttp { requests: Vec<ExpectedRequest> },
+}
+
+fn default_postgres_user() -> String {
+    "postgres".into()
+}
+
+fn default_postgres_database() -> String {
+    "spin_dev".into()
+}
+
+/// A request that a service is expected to have received
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectedRequest {
+    /// If not present, any method is allowed
+    pub method: Option<Method>,
+    /// The path including the query. If not present, any path is allowed
+    pub path: Option<String>,
+    /// Headers that are not listed are allowed
+    #[serde(default)]
+    pub headers: Vec<ResponseHeader>,
+    /// The expected body with the same shorthands as [`Response::body`]
+    ///
+    /// If not present, the body is not checked.
+    #[serde(default, deserialize_with = "deserialize_body_matcher")]
+    pub body: Option<BodyMatcher>,
+}
+
+impl TestConfig {
+    /// Start observing the services for the test's postconditions
+    ///
+    /// This must be called before the invocations are run so that nothing they cause (e.g.
+    /// MQTT messages being published) is missed.
+    pub fn watch_postconditions<R>(
+        &self,
+        env: &mut test_environment::TestEnvironment<R>,
+    ) -> anyhow::Result<PostconditionWatch> {
+        let mut subscriptions = HashMap::new();
+        for postcondition in &self.postconditions {
+            if let Postcondition::OutboundHttp { .. } = postcondition {
+                service(env, "http-echo")?.record_requests()?;
+            }
+            if let Postcondition::Mqtt { topic, .. } = postcondition {
+                if subscriptions.contains_key(topic) {
+                    continue;
+                }
+                let port = env
+                    .get_service_port("mqtt", 1883)?
+                    .context("mqtt service does not expose port 1883")?;
+                let subscription = crate::mqtt::Subscription::subscribe(
+                    test_environment::services::HOST,
+                    port,
+                    topic,
+                )?;
+                subscriptions.insert(topic.clone(), subscription);
+            }
+        }
+        Ok(PostconditionWatch {
+            postconditions: self.postconditions.clone(),
+            subscriptions,
+        })
+    }
+}
+
+/// The postconditions of a test and what's needed to observe them
+pub struct PostconditionWatch {
+    postconditions: Vec<Postcondition>,
+    subscriptions: HashMap<String, crate::mqtt::Subscription>,
+}
+
+impl PostconditionWatch {
+    /// Check that all postconditions hold, waiting a while for those that don't yet
+    pub fn check<R>(self, env: &mut test_environment::TestEnvironment<R>) -> anyhow::Result<()> {
+        let start = std::time::Instant::now();
+        for (index, postcondition) in self.postconditions.iter().enumerate() {
+            eventually(start, || self.check_one(postcondition, env))
+                .with_context(|| format!("postcondition {index} failed"))?;
+        }
+        Ok(())
+    }
+
+    fn check_one<R>(
+        &self,
+        postcondition: &Postcondition,
+        env: &mut test_environment::TestEnvironment<R>,
+    ) -> anyhow::Result<()> {
+        match postcondition {
+            Postcondition::Redis { key, value } => {
+                let port = env
+                    .get_service_port("redis", 6379)?
+                    .context("redis service does not expose port 6379")?;
+                let mut connection =
+                    crate::redis::Connection::open(test_environment::services::HOST, port)?;
+                let actual = connection.get(key)?;
+                anyhow::ensure!(
+                    actual.as_deref() == value.as_ref().map(|v| v.as_bytes()),
+                    "redis key '{key}' has unexpected value {:?} != {value:?}",
+                    actual.map(|a| String::from_utf8_lossy(&a).into_owned())
+                );
+            }
+            Postcondition::Mqtt { topic, messages } => {
+                let actual = self.subscriptions[topic].messages();
+                assert_received(&format!("mqtt topic '{topic}'"), &actual, messages)?;
+            }
+            Postcondition::Postgres {
+                query,
+                rows,
+                user,
+                database,
+            } => {
+                let output = service(env, "postgres")?.exec(&[
+                    "psql", "-U", user, "-d", database, "-At", "-F", "\x1f", "-c", query,
+                ])?;
+                anyhow::ensure!(
+                    output.status.success(),
+                    "postgres query failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                let actual = String::from_utf8(output.stdout)
+                    .context("postgres query output is not valid utf-8")?
+                    .lines()
+                    .map(|row| row.split('\x1f').map(str::to_owned).collect())
+                    .collect::<Vec<Vec<String>>>();
+                anyhow::ensure!(
+                    &actual == rows,
+                    "postgres query '{query}' returned unexpected rows {actual:?} != {rows:?}"
+                );
+            }
+            Postcondition::HttpEcho { received } => {
+                let actual = service(env, "http-echo")?.received()?;
+                assert_received("http-echo service", &actual, received)?;
+            }
+            Postcondition::TcpEcho { received } => {
+                let actual = service(env, "tcp-echo")?.received()?;
+                assert_received("tcp-echo service", &actual, received)?;
+            }
+            Postcondition::OutboundHttp { requests } => {
+                let actual = service(env, "http-echo")?.requests()?;
+                anyhow::ensure!(
+                    actual.len() == requests.len(),
+                    "http-echo service received {} requests but expected {}: {:?}",
+                    actual.len(),
+                    requests.len(),
+                    actual
+                        .iter()
+                        .map(|r| format!("{} {}", r.method, r.path))
+                        .collect::<Vec<_>>()
+                );
+                for (index, (expected, actual)) in requests.iter().zip(&actual).enumerate() {
+                    crate::assertions::assert_request(expected, actual)
+                        .with_context(|| format!("unexpected request {index}"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get the running service with the given name
+fn service<'a, R>(
+    env: &'a mut test_environment::TestEnvironment<R>,
+    name: &str,
+) -> anyhow::Result<&'a mut (dyn test_environment::services::Service + 'static)> {
+    env.services_mut()
+        .get_mut(name)
+        .with_context(|| format!("no {name} service is running"))
+}
+
+/// Assert that exactly the expected payloads were received
+fn assert_received(receiver: &str, actual: &[Vec<u8>], expected: &[String]) -> anyhow::Result<()> {
+    let actual = actual
+        .iter()
+        .map(|a| String::from_utf8_lossy(a))
+        .collect::<Vec<_>>();
+    anyhow::ensure!(
+        actual.len() == expected.len() && actual.iter().zip(expected).all(|(a, e)| a == e),
+        "{receiver} received {actual:?} but expected {expected:?}"
+    );
+    Ok(())
 }
 
 /// A precondition that must be met before the test can be run
@@ -182,8 +1002,93 @@ pub enum Precondition {
     Postgres,
 }
 
+impl Precondition {
+    /// The name of the builtin service which satisfies the precondition
+    ///
+    /// Returns `None` if the runtime itself is expected to satisfy the precondition.
+    pub fn service_name(&self) -> Option<&'static str> {
+        match self {
+            Precondition::HttpEcho => Some("http-echo"),
+            Precondition::TcpEcho => Some("tcp-echo"),
+            Precondition::Redis => Some("redis"),
+            Precondition::Mqtt => Some("mqtt"),
+            Precondition::Postgres => Some("postgres"),
+            Precondition::KeyValueStore(_) | Precondition::Sqlite => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct KeyValueStorePrecondition {
     pub label: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_matchers_must_stay_in_test_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let test_dir = dir.path().join("test");
+        std::fs::create_dir(&test_dir).unwrap();
+        std::fs::write(test_dir.join("body.txt"), "hello").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), test_dir.join("link.txt"))
+            .unwrap();
+
+        let resolve = |file: &str| {
+            let mut config = parse(&format!(
+                r#"{{ invocations: [{{ request: {{ path: "/" }}, response: {{ headers: [], body: {{ file: "{file}" }} }} }}] }}"#
+            ))
+            .unwrap();
+            config.resolve_paths(&test_dir)
+        };
+        resolve("body.txt").unwrap();
+        assert!(resolve("../secret.txt")
+            .unwrap_err()
+            .to_string()
+            .contains("outside of the test directory"));
+        assert!(resolve("missing.txt").is_err());
+        #[cfg(unix)]
+        assert!(resolve("link.txt")
+            .unwrap_err()
+            .to_string()
+            .contains("outside of the test directory"));
+    }
+
+    #[test]
+    fn variables_are_encoded_in_paths() {
+        let mut request: Request = json5::from_str(
+            r#"{ path: "/items/%{var=id}", headers: [{ name: "x-id", value: "%{var=id}" }], body: "%{var=id}" }"#,
+        )
+        .unwrap();
+        let variables = Variables::from([("id".to_owned(), "a/b c%{var=id}".to_owned())]);
+        request.substitute_variables(&variables).unwrap();
+        assert_eq!(request.path, "/items/a%2Fb%20c%25%7Bvar%3Did%7D");
+        assert_eq!(request.headers[0].value, "a/b c%{var=id}");
+        assert!(matches!(request.body, Some(HttpBody::Text(body)) if body == "a/b c%{var=id}"));
+    }
+
+    #[test]
+    fn postgres_postconditions_default_to_the_service_database() {
+        let postcondition = |fields: &str| -> Postcondition {
+            json5::from_str(&format!(
+                r#"{{ kind: "postgres", query: "SELECT 1", rows: [], {fields} }}"#
+            ))
+            .unwrap()
+        };
+        let Postcondition::Postgres { user, database, .. } = postcondition("") else {
+            unreachable!()
+        };
+        assert_eq!((user.as_str(), database.as_str()), ("postgres", "spin_dev"));
+        let Postcondition::Postgres { user, database, .. } =
+            postcondition(r#"user: "spin", database: "other""#)
+        else {
+            unreachable!()
+        };
+        assert_eq!((user.as_str(), database.as_str()), ("spin", "other"));
+    }
+}
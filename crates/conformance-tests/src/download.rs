@@ -0,0 +1,525 @@
+//! Fetching and caching of the conformance test archive
+
+use anyhow::Context as _;
+use sha2::Digest as _;
+use std::{
+    io::Read as _,
+    path::{Path, PathBuf},
+};
+
+/// Environment variable pointing at a local `tests.tar.gz` to use instead of downloading one
+pub const ARCHIVE_ENV_VAR: &str = "CONFORMANCE_TESTS_ARCHIVE";
+/// Environment variable with a base URL to download the tests from instead of GitHub
+pub const MIRROR_ENV_VAR: &str = "CONFORMANCE_TESTS_MIRROR";
+/// Environment variable with the directory the tests are cached in
+pub const CACHE_DIR_ENV_VAR: &str = "CONFORMANCE_TESTS_CACHE_DIR";
+
+const RELEASES_URL: &str = "https://github.com/fermyon/conformance-tests/releases/download";
+
+/// Where the test archive is fetched from
+#[derive(Debug, Clone)]
+pub enum ArchiveSource {
+    /// The GitHub release of the requested version
+    Release,
+    /// A mirror of the GitHub releases
+    ///
+    /// The archive is expected at `{base_url}/{version}/tests.tar.gz`.
+    Mirror(String),
+    /// An archive on the local file system
+    File(PathBuf),
+}
+
+impl ArchiveSource {
+    /// Determine the source from the environment, falling back to the GitHub release
+    pub fn from_env() -> Self {
+        if let Some(path) = std::env::var_os(ARCHIVE_ENV_VAR) {
+            Self::File(path.into())
+        } else if let Ok(base_url) = std::env::var(MIRROR_ENV_VAR) {
+            Self::Mirror(base_url)
+        } else {
+            Self::Release
+        }
+    }
+
+    /// Read the full archive for the given version
+    fn fetch(&self, version: &str) -> anyhow::Result<Vec<u8>> {
+        let url = match self {
+            Self::File(path) => {
+                return std::fs::read(path)
+                    .with_context(|| format!("failed to read test archive {path:?}"))
+            }
+            Self::Release => format!("{RELEASES_URL}/{version}/tests.tar.gz"),
+            Self::Mirror(base_url) => {
+                format!("{}/{version}/tests.tar.gz", base_url.trim_end_matches('/'))
+            }
+        };
+        let mut response = reqwest::blocking::get(&url)
+            .with_context(|| format!("failed to send request to '{url}'"))?
+            .error_for_status()?;
+        let mut archive = Vec::new();
+        response
+            .read_to_end(&mut archive)
+            .context("failed to read test archive from response")?;
+        Ok(archive)
+    }
+}
+
+/// The default directory the tests are cached in
+pub fn default_cache_dir() -> PathBuf {
+    std::env::var_os(CACHE_DIR_ENV_VAR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("conformance-tests"))
+}
+
+/// Fetch the tests for `version` into a per version directory under `cache_dir`
+///
+/// Each archive is extracted into a directory named after its checksum which is never modified
+/// afterwards. Extractions of other archives of the version are removed once a new one is in
+/// place. Released versions never change, so a cached archive whose checksum still matches is
+/// used without going to the network at all.
+pub(crate) fn fetch_tests(
+    source: &ArchiveSource,
+    version: &str,
+    cache_dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let version_dir = cache_dir.join(sanitize(version));
+    std::fs::create_dir_all(&version_dir)
+        .with_context(|| format!("failed to create cache directory {version_dir:?}"))?;
+    // Held while the cache is read and updated so that concurrent runs don't trample each other
+    let mut lock = fslock::LockFile::open(&version_dir.join(".lock"))
+        .context("failed to open cache lock file")?;
+    lock.lock().context("failed to obtain cache lock")?;
+
+    let cached = cached_tests(&version_dir);
+    let immutable = version != "canary";
+    if let Some(tests_dir) = &cached {
+        if immutable && !matches!(source, ArchiveSource::File(_)) {
+            return Ok(tests_dir.clone());
+        }
+    }
+
+    let archive = match source.fetch(version) {
+        Ok(archive) => archive,
+        Err(e) => match cached {
+            // A previous extraction can stand in for an archive that may have changed when offline
+            Some(tests_dir) if !immutable => {
+                eprintln!("WARNING: failed to fetch tests {version}, using cached copy in {tests_dir:?}: {e:#}");
+                return Ok(tests_dir);
+            }
+            _ => return Err(e),
+        },
+    };
+    let checksum = format!("{:x}", sha2::Sha256::digest(&archive));
+    let tests_dir = version_dir.join(&checksum);
+    if !tests_dir.is_dir() {
+        // Extract next to the cache and move it in place only once extraction has succeeded
+        let staging_dir = version_dir.join(format!("{checksum}.staging"));
+        if staging_dir.exists() {
+            std::fs::remove_dir_all(&staging_dir)
+                .with_context(|| format!("failed to remove stale directory {staging_dir:?}"))?;
+        }
+        extract(&archive, &staging_dir, MAX_EXTRACTED_SIZE)?;
+        std::fs::rename(&staging_dir, &tests_dir)
+            .with_context(|| format!("failed to move extracted tests to {tests_dir:?}"))?;
+    }
+    std::fs::write(version_dir.join(ARCHIVE_FILE_NAME), &archive)
+        .context("failed to cache test archive")?;
+    std::fs::write(version_dir.join(CHECKSUM_FILE_NAME), &checksum)
+        .context("failed to write archive checksum")?;
+    remove_outdated(&version_dir, &tests_dir);
+    Ok(tests_dir)
+}
+
+/// Remove the extractions of other archives (and any stale staging directories) of a version
+///
+/// This keeps the cache of versions that change (i.e. `canary`) from growing without bound.
+fn remove_outdated(version_dir: &Path, tests_dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(version_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path == tests_dir || !path.is_dir() {
+            continue;
+        }
+        if let Err(e) = std::fs::remove_dir_all(&path) {
+            eprintln!("WARNING: failed to remove outdated tests in {path:?}: {e}");
+        }
+    }
+}
+
+/// The cached copy of the archive a version's tests were last extracted from
+const ARCHIVE_FILE_NAME: &str = "tests.tar.gz";
+/// The SHA-256 checksum of the cached archive
+const CHECKSUM_FILE_NAME: &str = "tests.tar.gz.sha256";
+
+/// The extracted tests in the cache, if the cached archive still matches its checksum
+fn cached_tests(version_dir: &Path) -> Option<PathBuf> {
+    let checksum = std::fs::read_to_string(version_dir.join(CHECKSUM_FILE_NAME)).ok()?;
+    let archive = std::fs::read(version_dir.join(ARCHIVE_FILE_NAME)).ok()?;
+    if format!("{:x}", sha2::Sha256::digest(&archive)) != checksum.trim() {
+        return None;
+    }
+    let tests_dir = version_dir.join(checksum.trim());
+    tests_dir.is_dir().then_some(tests_dir)
+}
+
+/// The maximum number of bytes an archive may expand to
+pub const MAX_EXTRACTED_SIZE: u64 = 512 * 1024 * 1024;
+
+/// An error extracting the test archive
+///
+/// Fetching the tests returns an [`anyhow::Error`], so callers that want to tell why an archive
+/// was rejected must downcast it, e.g. `error.downcast_ref::<ExtractError>()`.
+#[derive(Debug)]
+pub enum ExtractError {
+    /// An entry's path is absolute or leaves the extraction directory
+    UnsafePath(PathBuf),
+    /// A symlink or hardlink points outside of the extraction directory
+    LinkEscapesRoot { path: PathBuf, target: PathBuf },
+    /// The archive expands to more than [`MAX_EXTRACTED_SIZE`] bytes
+    TooLarge { limit: u64 },
+    /// Reading the archive or writing its contents failed
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsafePath(path) => {
+                write!(f, "archive entry {path:?} is outside of the extraction directory")
+            }
+            Self::LinkEscapesRoot { path, target } => write!(
+                f,
+                "archive entry {path:?} links to {target:?} which is outside of the extraction directory"
+            ),
+            Self::TooLarge { limit } => write!(f, "archive expands to more than {limit} bytes"),
+            Self::Io(_) => write!(f, "failed to extract test archive"),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ExtractError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Extract a gzipped tarball into `dir`
+///
+/// Only directories and regular files are extracted. Links are skipped, but the archive is
+/// rejected if any of them point outside of `dir`.
+fn extract(archive: &[u8], dir: &Path, limit: u64) -> Result<(), ExtractError> {
+    let archive = flate2::read::GzDecoder::new(archive);
+    let mut remaining = limit;
+    for entry in tar::Archive::new(archive).entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let relative_path =
+            normalize(&entry_path).ok_or_else(|| ExtractError::UnsafePath(entry_path.clone()))?;
+        let path = dir.join(&relative_path);
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => std::fs::create_dir_all(&path)?,
+            tar::EntryType::Regular => {
+                if entry.header().size()? > remaining {
+                    return Err(ExtractError::TooLarge { limit });
+                }
+                if let Some(parent_dir) = path.parent() {
+                    std::fs::create_dir_all(parent_dir)?;
+                }
+                let mut file = std::fs::File::create(&path)?;
+                // Don't trust the header's size and stop copying once the limit is reached
+                let copied = std::io::copy(
+                    &mut (&mut entry).take(remaining.saturating_add(1)),
+                    &mut file,
+                )?;
+                if copied > remaining {
+                    return Err(ExtractError::TooLarge { limit });
+                }
+                remaining -= copied;
+            }
+            kind @ (tar::EntryType::Symlink | tar::EntryType::Link) => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| ExtractError::UnsafePath(entry_path.clone()))?
+                    .into_owned();
+                // Symlinks are relative to the directory they're in while hardlinks are
+                // relative to the root of the archive
+                let resolved = if kind == tar::EntryType::Symlink {
+                    relative_path
+                        .parent()
+                        .map(|parent| parent.join(&target))
+                        .unwrap_or_else(|| target.clone())
+                } else {
+                    target.clone()
+                };
+                if normalize(&resolved).is_none() {
+                    return Err(ExtractError::LinkEscapesRoot {
+                        path: entry_path,
+                        target,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Lexically normalize a relative path, returning `None` if it's absolute or leaves its root
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(c) => normalized.push(c),
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Make a version safe to use as a directory name
+fn sanitize(version: &str) -> String {
+    if version.chars().all(|c| c == '.') {
+        return "_".repeat(version.len().max(1));
+    }
+    version
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a gzipped tarball with a regular file for each of the entries
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    /// Build a gzipped tarball from raw entries of a path, type, link target and contents
+    ///
+    /// The paths are written into the headers as is since the `tar` crate refuses to build
+    /// archives with unsafe paths.
+    fn crafted_archive(entries: &[(&str, tar::EntryType, &str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        for (path, kind, link, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..path.len()].copy_from_slice(path.as_bytes());
+            gnu.linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(*kind);
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *contents).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn extract_crafted(entries: &[(&str, tar::EntryType, &str, &[u8])]) -> ExtractError {
+        let dir = tempfile::tempdir().unwrap();
+        extract(&crafted_archive(entries), &dir.path().join("tests"), 1024).unwrap_err()
+    }
+
+    #[test]
+    fn extracts_files_and_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = crafted_archive(&[
+            ("./test/", tar::EntryType::Directory, "", b""),
+            ("./test/test.json5", tar::EntryType::Regular, "", b"{}"),
+            ("./test/link", tar::EntryType::Symlink, "test.json5", b""),
+        ]);
+        extract(&archive, dir.path(), 1024).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("test/test.json5")).unwrap(),
+            "{}"
+        );
+        // Links are skipped
+        assert!(!dir.path().join("test/link").exists());
+    }
+
+    #[test]
+    fn rejects_parent_dir_entries() {
+        let error = extract_crafted(&[("test/../../evil", tar::EntryType::Regular, "", b"x")]);
+        assert!(matches!(error, ExtractError::UnsafePath(_)), "{error}");
+    }
+
+    #[test]
+    fn rejects_absolute_entries() {
+        let error = extract_crafted(&[("/tmp/evil", tar::EntryType::Regular, "", b"x")]);
+        assert!(matches!(error, ExtractError::UnsafePath(_)), "{error}");
+    }
+
+    #[test]
+    fn rejects_symlinks_escaping_root() {
+        for target in ["../../etc/passwd", "/etc/passwd"] {
+            let error = extract_crafted(&[("test/link", tar::EntryType::Symlink, target, b"")]);
+            assert!(
+                matches!(error, ExtractError::LinkEscapesRoot { .. }),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_hardlinks_escaping_root() {
+        for target in ["../etc/passwd", "/etc/passwd"] {
+            let error = extract_crafted(&[("test/link", tar::EntryType::Link, target, b"")]);
+            assert!(
+                matches!(error, ExtractError::LinkEscapesRoot { .. }),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_archives_over_limit() {
+        let large = vec![0; 1025];
+        let error = extract_crafted(&[("test/large", tar::EntryType::Regular, "", &large)]);
+        assert!(
+            matches!(error, ExtractError::TooLarge { limit: 1024 }),
+            "{error}"
+        );
+        // The limit applies to the archive as a whole rather than to each file
+        let half = vec![0; 600];
+        let error = extract_crafted(&[
+            ("test/a", tar::EntryType::Regular, "", &half),
+            ("test/b", tar::EntryType::Regular, "", &half),
+        ]);
+        assert!(
+            matches!(error, ExtractError::TooLarge { limit: 1024 }),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn extract_error_can_be_downcast_from_fetch() {
+        let cache = tempfile::tempdir().unwrap();
+        let path = cache.path().join("tests.tar.gz");
+        std::fs::write(
+            &path,
+            crafted_archive(&[("../evil", tar::EntryType::Regular, "", b"x")]),
+        )
+        .unwrap();
+        let error = fetch_tests(&ArchiveSource::File(path), "v0.1.0", cache.path()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ExtractError>(),
+            Some(ExtractError::UnsafePath(_))
+        ));
+    }
+
+    /// A mirror that can't be reached
+    fn offline() -> ArchiveSource {
+        // Nothing listens on port 1 so the connection is refused straight away
+        ArchiveSource::Mirror("http://127.0.0.1:1".into())
+    }
+
+    fn file_source(dir: &Path, contents: &str) -> ArchiveSource {
+        let path = dir.join("tests.tar.gz");
+        std::fs::write(&path, archive(&[("test/test.json5", contents)])).unwrap();
+        ArchiveSource::File(path)
+    }
+
+    #[test]
+    fn release_is_served_from_cache_without_fetching() {
+        let cache = tempfile::tempdir().unwrap();
+        let source = file_source(cache.path(), "{}");
+        let tests_dir = fetch_tests(&source, "v0.1.0", cache.path()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(tests_dir.join("test/test.json5")).unwrap(),
+            "{}"
+        );
+        assert_eq!(
+            fetch_tests(&offline(), "v0.1.0", cache.path()).unwrap(),
+            tests_dir
+        );
+    }
+
+    #[test]
+    fn corrupted_cache_is_not_used() {
+        let cache = tempfile::tempdir().unwrap();
+        let source = file_source(cache.path(), "{}");
+        fetch_tests(&source, "v0.1.0", cache.path()).unwrap();
+        std::fs::write(
+            cache.path().join("v0.1.0").join(ARCHIVE_FILE_NAME),
+            "corrupt",
+        )
+        .unwrap();
+        assert!(fetch_tests(&offline(), "v0.1.0", cache.path()).is_err());
+    }
+
+    #[test]
+    fn canary_falls_back_to_cache_when_offline() {
+        let cache = tempfile::tempdir().unwrap();
+        let source = file_source(cache.path(), "{}");
+        let tests_dir = fetch_tests(&source, "canary", cache.path()).unwrap();
+        assert_eq!(
+            fetch_tests(&offline(), "canary", cache.path()).unwrap(),
+            tests_dir
+        );
+    }
+
+    #[test]
+    fn uncached_version_fails_when_offline() {
+        let cache = tempfile::tempdir().unwrap();
+        assert!(fetch_tests(&offline(), "v0.1.0", cache.path()).is_err());
+        assert!(fetch_tests(&offline(), "canary", cache.path()).is_err());
+    }
+
+    #[test]
+    fn changed_canary_replaces_previous_tests() {
+        let cache = tempfile::tempdir().unwrap();
+        let old = fetch_tests(&file_source(cache.path(), "old"), "canary", cache.path()).unwrap();
+        // A staging directory left behind by an interrupted extraction
+        let stale = cache.path().join("canary/0123.staging");
+        std::fs::create_dir_all(&stale).unwrap();
+        let new = fetch_tests(&file_source(cache.path(), "new"), "canary", cache.path()).unwrap();
+        assert_ne!(old, new);
+        assert!(!old.exists());
+        assert!(!stale.exists());
+        assert_eq!(
+            std::fs::read_to_string(new.join("test/test.json5")).unwrap(),
+            "new"
+        );
+        // The cache now points at the new tests
+        assert_eq!(
+            fetch_tests(&offline(), "canary", cache.path()).unwrap(),
+            new
+        );
+    }
+}
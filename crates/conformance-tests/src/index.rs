@@ -0,0 +1,286 @@
+//! An index of the test files and their digests used to verify the integrity of the tests
+
+use anyhow::Context as _;
+use sha2::Digest as _;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// The file name of the index at the root of the tests directory
+pub const INDEX_FILE_NAME: &str = "index.json";
+/// The file name of the detached signature of the index
+pub const SIGNATURE_FILE_NAME: &str = "index.json.sig";
+
+/// A listing of every test, its files and their SHA-256 digests
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Index {
+    /// The tests by name
+    pub tests: BTreeMap<String, IndexedTest>,
+}
+
+/// A test in the index
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IndexedTest {
+    /// The hex encoded SHA-256 digest of every file by its `/` separated path within the test
+    pub files: BTreeMap<String, String>,
+}
+
+impl Index {
+    /// Create an index of all the tests in a directory
+    pub fn generate(tests_dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut tests = BTreeMap::new();
+        for (name, test_dir) in test_dirs(tests_dir.as_ref())? {
+            let mut files = BTreeMap::new();
+            for path in files_in(&test_dir)? {
+                let relative = path
+                    .strip_prefix(&test_dir)
+                    .expect("file should be inside of the test directory");
+                let relative = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                files.insert(relative, digest(&path)?);
+            }
+            tests.insert(name, IndexedTest { files });
+        }
+        Ok(Self { tests })
+    }
+
+    /// Read the index from a tests directory
+    pub fn from_dir(tests_dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = tests_dir.as_ref().join(INDEX_FILE_NAME);
+        let index = std::fs::read(&path).with_context(|| format!("failed to read {path:?}"))?;
+        serde_json::from_slice(&index).context("test index could not be parsed")
+    }
+
+    /// Serialize the index to the bytes written to [`INDEX_FILE_NAME`]
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_json::to_vec_pretty(self).context("failed to serialize test index")
+    }
+
+    /// Check that the tests in the directory are exactly the ones listed in the index
+    pub fn verify(&self, tests_dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        let actual = Self::generate(tests_dir)?;
+        for (name, test) in &self.tests {
+            let actual_test = actual
+                .tests
+                .get(name)
+                .with_context(|| format!("test '{name}' is listed in the index but missing"))?;
+            for (file, expected_digest) in &test.files {
+                let actual_digest = actual_test.files.get(file).with_context(|| {
+                    format!("file '{file}' of test '{name}' is listed in the index but missing")
+                })?;
+                anyhow::ensure!(
+                    actual_digest == expected_digest,
+                    "file '{file}' of test '{name}' does not match the digest in the index"
+                );
+            }
+            if let Some(file) = actual_test
+                .files
+                .keys()
+                .find(|f| !test.files.contains_key(*f))
+            {
+                anyhow::bail!("file '{file}' of test '{name}' is not listed in the index");
+            }
+        }
+        if let Some(name) = actual.tests.keys().find(|t| !self.tests.contains_key(*t)) {
+            anyhow::bail!("test '{name}' is not listed in the index");
+        }
+        Ok(())
+    }
+}
+
+/// Sign the serialized index with an ed25519 secret key
+///
+/// Returns the hex encoded signature as written to [`SIGNATURE_FILE_NAME`].
+pub fn sign(index: &[u8], secret_key: &[u8; 32]) -> String {
+    use ed25519_dalek::Signer as _;
+    let key = ed25519_dalek::SigningKey::from_bytes(secret_key);
+    hex::encode(key.sign(index).to_bytes())
+}
+
+/// The public key belonging to an ed25519 secret key
+pub fn public_key(secret_key: &[u8; 32]) -> [u8; 32] {
+    ed25519_dalek::SigningKey::from_bytes(secret_key)
+        .verifying_key()
+        .to_bytes()
+}
+
+/// Verify the signature of the index in the tests directory and that the tests match it
+pub fn verify_dir(tests_dir: impl AsRef<Path>, public_key: &[u8; 32]) -> anyhow::Result<()> {
+    let tests_dir = tests_dir.as_ref();
+    let index_path = tests_dir.join(INDEX_FILE_NAME);
+    let index = std::fs::read(&index_path)
+        .with_context(|| format!("failed to read test index {index_path:?}"))?;
+    let signature_path = tests_dir.join(SIGNATURE_FILE_NAME);
+    let signature = std::fs::read_to_string(&signature_path)
+        .with_context(|| format!("failed to read test index signature {signature_path:?}"))?;
+    let signature: [u8; 64] = hex::decode(signature.trim())
+        .ok()
+        .and_then(|s| s.try_into().ok())
+        .context("test index signature is not a hex encoded ed25519 signature")?;
+    ed25519_dalek::VerifyingKey::from_bytes(public_key)
+        .context("invalid ed25519 public key")?
+        .verify_strict(&index, &ed25519_dalek::Signature::from_bytes(&signature))
+        .context("test index signature is invalid")?;
+    let index: Index = serde_json::from_slice(&index).context("test index could not be parsed")?;
+    index.verify(tests_dir)
+}
+
+/// Parse a hex encoded 32 byte ed25519 key
+pub fn parse_key(key: &str) -> anyhow::Result<[u8; 32]> {
+    hex::decode(key.trim())
+        .ok()
+        .and_then(|k| k.try_into().ok())
+        .context("key is not a hex encoded 32 byte ed25519 key")
+}
+
+/// The SHA-256 digest of a file
+fn digest(path: &Path) -> anyhow::Result<String> {
+    let contents = std::fs::read(path).with_context(|| format!("failed to read {path:?}"))?;
+    Ok(hex::encode(sha2::Sha256::digest(contents)))
+}
+
+/// All the test directories by test name
+fn test_dirs(tests_dir: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(tests_dir)
+        .with_context(|| format!("failed to read tests directory {tests_dir:?}"))?
+    {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .context("could not determine test name")?
+            .to_owned();
+        dirs.push((name, path));
+    }
+    Ok(dirs)
+}
+
+/// All the files in a directory and its subdirectories
+fn files_in(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("failed to read {dir:?}"))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(files_in(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET_KEY: [u8; 32] = [7; 32];
+
+    /// Write a test directory with a signed index
+    fn signed_tests() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let test_dir = dir.path().join("request-shape");
+        std::fs::create_dir_all(test_dir.join("assets")).unwrap();
+        std::fs::write(test_dir.join("test.json5"), "{}").unwrap();
+        std::fs::write(test_dir.join("spin.toml"), "spin_manifest_version = 2").unwrap();
+        std::fs::write(test_dir.join("assets/body.txt"), "hello").unwrap();
+        let index = Index::generate(dir.path()).unwrap().to_bytes().unwrap();
+        std::fs::write(dir.path().join(INDEX_FILE_NAME), &index).unwrap();
+        std::fs::write(
+            dir.path().join(SIGNATURE_FILE_NAME),
+            sign(&index, &SECRET_KEY),
+        )
+        .unwrap();
+        dir
+    }
+
+    fn verify(dir: &tempfile::TempDir) -> anyhow::Result<()> {
+        verify_dir(dir.path(), &public_key(&SECRET_KEY))
+    }
+
+    #[test]
+    fn signed_tests_verify() {
+        let dir = signed_tests();
+        verify(&dir).unwrap();
+        let index = Index::from_dir(dir.path()).unwrap();
+        assert_eq!(
+            index.tests["request-shape"]
+                .files
+                .keys()
+                .collect::<Vec<_>>(),
+            ["assets/body.txt", "spin.toml", "test.json5"]
+        );
+    }
+
+    #[test]
+    fn tampered_file_fails() {
+        let dir = signed_tests();
+        std::fs::write(dir.path().join("request-shape/test.json5"), "{ }").unwrap();
+        let error = verify(&dir).unwrap_err().to_string();
+        assert!(error.contains("does not match the digest"), "{error}");
+    }
+
+    #[test]
+    fn extra_file_fails() {
+        let dir = signed_tests();
+        std::fs::write(dir.path().join("request-shape/extra.wasm"), "").unwrap();
+        let error = verify(&dir).unwrap_err().to_string();
+        assert!(error.contains("is not listed in the index"), "{error}");
+    }
+
+    #[test]
+    fn extra_test_fails() {
+        let dir = signed_tests();
+        std::fs::create_dir(dir.path().join("extra")).unwrap();
+        let error = verify(&dir).unwrap_err().to_string();
+        assert!(error.contains("test 'extra' is not listed"), "{error}");
+    }
+
+    #[test]
+    fn missing_file_fails() {
+        let dir = signed_tests();
+        std::fs::remove_file(dir.path().join("request-shape/assets/body.txt")).unwrap();
+        let error = verify(&dir).unwrap_err().to_string();
+        assert!(error.contains("listed in the index but missing"), "{error}");
+    }
+
+    #[test]
+    fn tampered_index_fails() {
+        let dir = signed_tests();
+        let index_path = dir.path().join(INDEX_FILE_NAME);
+        let index = std::fs::read_to_string(&index_path).unwrap();
+        std::fs::write(&index_path, index.replace("body.txt", "other.txt")).unwrap();
+        let error = verify(&dir).unwrap_err().to_string();
+        assert!(error.contains("signature is invalid"), "{error}");
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let dir = signed_tests();
+        let error = verify_dir(dir.path(), &public_key(&[8; 32]))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("signature is invalid"), "{error}");
+    }
+
+    #[test]
+    fn missing_signature_fails() {
+        let dir = signed_tests();
+        std::fs::remove_file(dir.path().join(SIGNATURE_FILE_NAME)).unwrap();
+        assert!(verify(&dir).is_err());
+    }
+
+    #[test]
+    fn keys_round_trip_through_hex() {
+        let key = parse_key(&format!("{}\n", hex::encode(SECRET_KEY))).unwrap();
+        assert_eq!(key, SECRET_KEY);
+        assert!(parse_key("abcd").is_err());
+    }
+}
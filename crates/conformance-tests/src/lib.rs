@@ -1,12 +1,35 @@
 pub mod config;
+mod download;
+pub mod index;
+mod mqtt;
+mod redis;
+pub mod report;
+
+pub use download::{
+    default_cache_dir, ArchiveSource, ExtractError, ARCHIVE_ENV_VAR, CACHE_DIR_ENV_VAR,
+    MAX_EXTRACTED_SIZE, MIRROR_ENV_VAR,
+};
+pub use libtest_mimic;
 
 use anyhow::Context as _;
+use report::{Outcome, Report, ReportFormat, TestReport};
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use test_environment::{manifest_template::EnvTemplate, TestEnvironment};
 
 /// Configuration for how tests are run.
 pub struct Config {
     version: String,
     ignored: Vec<String>,
+    expected_failures: HashMap<String, String>,
+    archive_source: Option<ArchiveSource>,
+    cache_dir: Option<PathBuf>,
+    public_key: Option<[u8; 32]>,
+    args: libtest_mimic::Arguments,
+    reports: Vec<(ReportFormat, PathBuf)>,
+    capabilities: Option<Vec<config::Capability>>,
+    spin_version: Option<semver::Version>,
 }
 
 impl Config {
@@ -17,6 +40,14 @@ impl Config {
         Self {
             version: version.into(),
             ignored: Vec::new(),
+            expected_failures: HashMap::new(),
+            archive_source: None,
+            cache_dir: None,
+            public_key: None,
+            args: libtest_mimic::Arguments::default(),
+            reports: Vec::new(),
+            capabilities: None,
+            spin_version: None,
         }
     }
 
@@ -31,6 +62,140 @@ impl Config {
         self.ignored.extend(names.into_iter().map(Into::into));
         self
     }
+
+    /// Expect a test to fail for the given reason
+    ///
+    /// Unlike ignored tests, the test is still run. It passes if it fails and fails with an
+    /// "unexpected pass" error if it passes so that the expectation can be removed.
+    pub fn expect_failure(mut self, name: impl Into<String>, reason: impl Into<String>) -> Self {
+        self.expected_failures.insert(name.into(), reason.into());
+        self
+    }
+
+    /// Set where the test archive is fetched from
+    ///
+    /// Defaults to the source configured through the environment (see [`ArchiveSource::from_env`]).
+    pub fn archive_source(mut self, source: ArchiveSource) -> Self {
+        self.archive_source = Some(source);
+        self
+    }
+
+    /// Set the directory the downloaded tests are cached in
+    ///
+    /// Defaults to [`default_cache_dir`].
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Set the arguments controlling how tests are filtered, run and reported
+    ///
+    /// These are the same arguments `cargo test` accepts (e.g. `--exact`, `--list`, `--include-ignored`,
+    /// `--test-threads` or `--format`).
+    pub fn args(mut self, args: libtest_mimic::Arguments) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Parse the arguments controlling how tests are run from the process' arguments
+    ///
+    /// Exits the process with an error message if the arguments are invalid.
+    pub fn args_from_env(self) -> Self {
+        self.args(libtest_mimic::Arguments::from_args())
+    }
+
+    /// Declare the capabilities the runtime supports (e.g. `spin:postgres@4.0.0` or `wasi:http@0.2.0`)
+    ///
+    /// Tests requiring a capability that isn't declared are ignored. If this is never called,
+    /// the runtime is assumed to support every capability. Errors if a capability is malformed.
+    pub fn capabilities(
+        mut self,
+        capabilities: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> anyhow::Result<Self> {
+        let capabilities = capabilities
+            .into_iter()
+            .map(|c| c.as_ref().parse())
+            .collect::<anyhow::Result<Vec<config::Capability>>>()
+            .context("invalid runtime capability")?;
+        self.capabilities
+            .get_or_insert_with(Vec::new)
+            .extend(capabilities);
+        Ok(self)
+    }
+
+    /// Declare the version of Spin the runtime is compatible with
+    ///
+    /// Tests with a higher `min-spin-version` are ignored. Errors if the version isn't valid semver.
+    pub fn spin_version(mut self, version: &str) -> anyhow::Result<Self> {
+        self.spin_version = Some(
+            semver::Version::parse(version)
+                .with_context(|| format!("invalid Spin version '{version}'"))?,
+        );
+        Ok(self)
+    }
+
+    /// Write a report of the test run to the given path once all tests have run
+    ///
+    /// Can be called multiple times to write several reports.
+    pub fn report(mut self, format: ReportFormat, path: impl Into<PathBuf>) -> Self {
+        self.reports.push((format, path.into()));
+        self
+    }
+
+    /// Require the tests to be signed by the ed25519 key with the given public key
+    ///
+    /// The signature of the test index and the digests of all test files are then verified
+    /// before any test is run. See the [`index`] module for details.
+    pub fn public_key(mut self, public_key: [u8; 32]) -> Self {
+        self.public_key = Some(public_key);
+        self
+    }
+
+    /// Download the tests for the configured version and return the directory they are written to
+    ///
+    /// Each version is cached in its own directory. Released versions are only downloaded once,
+    /// while `canary` is fetched again on every call and reuses the cached extraction if the
+    /// archive hasn't changed.
+    pub fn download_tests(&self) -> anyhow::Result<PathBuf> {
+        let tests_dir = self.fetch_tests()?;
+        self.verify(&tests_dir)?;
+        Ok(tests_dir)
+    }
+
+    fn fetch_tests(&self) -> anyhow::Result<PathBuf> {
+        let source = self
+            .archive_source
+            .clone()
+            .unwrap_or_else(ArchiveSource::from_env);
+        let cache_dir = self.cache_dir.clone().unwrap_or_else(default_cache_dir);
+        download::fetch_tests(&source, &self.version, &cache_dir)
+    }
+
+    /// The reason a test does not apply to the runtime, if any
+    fn skip_reason(&self, test: &config::TestConfig) -> Option<String> {
+        if let (Some(min), Some(version)) = (&test.min_spin_version, &self.spin_version) {
+            if version < min {
+                return Some(format!("requires Spin {min}"));
+            }
+        }
+        let capabilities = self.capabilities.as_ref()?;
+        let missing = test
+            .requires
+            .iter()
+            .filter(|required| !capabilities.iter().any(|c| c.satisfies(required)))
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        (!missing.is_empty()).then(|| format!("requires {}", missing.join(", ")))
+    }
+
+    /// Verify the tests against the signed index if a public key is configured
+    fn verify(&self, tests_dir: &Path) -> anyhow::Result<()> {
+        if let Some(public_key) = &self.public_key {
+            index::verify_dir(tests_dir, public_key)
+                .with_context(|| format!("failed to verify tests in {tests_dir:?}"))?;
+        }
+        Ok(())
+    }
 }
 
 /// Run the conformance tests and return the results.
@@ -38,7 +203,8 @@ pub fn run_tests(
     config: Config,
     run: impl Fn(Test) -> anyhow::Result<()> + Send + Clone + 'static,
 ) -> anyhow::Result<libtest_mimic::Conclusion> {
-    let tests_dir = download_tests(&config.version)?;
+    // The tests are verified when they are run
+    let tests_dir = config.fetch_tests()?;
     run_tests_from(tests_dir, config, run)
 }
 
@@ -48,49 +214,106 @@ pub fn run_tests_from(
     config: Config,
     run: impl Fn(Test) -> anyhow::Result<()> + Send + Clone + 'static,
 ) -> anyhow::Result<libtest_mimic::Conclusion> {
-    let trials = tests_iter(tests_dir)?
+    config.verify(tests_dir.as_ref())?;
+    let report = Arc::new(Mutex::new(Report::default()));
+    let (trials, skip_reasons): (Vec<_>, Vec<_>) = tests_iter(tests_dir)?
         .map(|test| {
             let run = run.clone();
             let name = test.name.clone();
-            libtest_mimic::Trial::test(&name, move || Ok(run(test).map_err(FullError::from)?))
-                .with_ignored_flag(config.ignored.contains(&name))
+            let skip_reason = config.skip_reason(&test.config);
+            let ignored = config.ignored.contains(&name) || skip_reason.is_some();
+            if ignored {
+                // Replaced below should the test be run anyway (e.g. with `--include-ignored`)
+                let mut test_report = TestReport::new(&name, Outcome::Ignored, Default::default());
+                test_report.reason = skip_reason.clone();
+                report.lock().unwrap().tests.push(test_report);
+            }
+            let expected_failure = config.expected_failures.get(&name).cloned();
+            let kind = if expected_failure.is_some() {
+                "xfail"
+            } else {
+                ""
+            };
+            let report = report.clone();
+            let trial = libtest_mimic::Trial::test(name.clone(), move || {
+                let start = std::time::Instant::now();
+                let result = run(test);
+                let (test_report, result) = match (result, expected_failure) {
+                    (Ok(()), Some(reason)) => {
+                        let error = anyhow::anyhow!(
+                            "unexpected pass: test was expected to fail ({reason}) but it passed"
+                        );
+                        let test_report = TestReport::new(&name, Outcome::Failed, start.elapsed())
+                            .with_failure(&error);
+                        (test_report, Err(error.to_string().into()))
+                    }
+                    (Err(error), Some(reason)) => {
+                        let mut test_report =
+                            TestReport::new(&name, Outcome::ExpectedFailure, start.elapsed())
+                                .with_failure(&error);
+                        test_report.reason = Some(reason);
+                        (test_report, Ok(()))
+                    }
+                    (Ok(()), None) => (
+                        TestReport::new(&name, Outcome::Passed, start.elapsed()),
+                        Ok(()),
+                    ),
+                    (Err(error), None) => {
+                        let test_report = TestReport::new(&name, Outcome::Failed, start.elapsed())
+                            .with_failure(&error);
+                        (test_report, Err(FullError::from(error).into()))
+                    }
+                };
+                let mut report = report.lock().unwrap();
+                report.tests.retain(|t| t.name != name);
+                report.tests.push(test_report);
+                result
+            })
+            .with_kind(kind)
+            .with_ignored_flag(ignored);
+            (trial, skip_reason)
         })
-        .collect();
-    Ok(libtest_mimic::run(&Default::default(), trials))
+        .unzip();
+    // The harness has no way to show why a test is ignored so it's noted before the run
+    let args = &config.args;
+    if !args.list && !args.ignored && !args.include_ignored {
+        for (trial, reason) in trials.iter().zip(&skip_reasons) {
+            if let (Some(reason), true) = (reason, is_selected(args, trial)) {
+                eprintln!("note: ignoring test '{}': {reason}", trial.name());
+            }
+        }
+    }
+    let conclusion = libtest_mimic::run(&config.args, trials);
+    let mut report = std::mem::take(&mut *report.lock().unwrap());
+    report.tests.sort_by(|a, b| a.name.cmp(&b.name));
+    for (format, path) in &config.reports {
+        report.write(*format, path)?;
+    }
+    Ok(conclusion)
+}
+
+/// Whether the trial passes the harness' name filters in the same way `libtest_mimic` applies them
+fn is_selected(args: &libtest_mimic::Arguments, trial: &libtest_mimic::Trial) -> bool {
+    let name = trial.name();
+    let name_with_kind = match trial.kind() {
+        "" => name.to_owned(),
+        kind => format!("[{kind}] {name}"),
+    };
+    let matches = |pattern: &str| {
+        if args.exact {
+            name == pattern || name_with_kind == pattern
+        } else {
+            name_with_kind.contains(pattern)
+        }
+    };
+    args.filter.as_deref().is_none_or(matches) && !args.skip.iter().any(|s| matches(s))
 }
 
 /// Download the conformance tests and return the path to the directory where they are written to
+///
+/// See [`Config::download_tests`] for more control over where the tests are fetched from.
 pub fn download_tests(version: &str) -> anyhow::Result<std::path::PathBuf> {
-    let response = reqwest::blocking::get(format!(
-        "https://github.com/fermyon/conformance-tests/releases/download/{version}/tests.tar.gz"
-    ))
-    .context("failed to send request")?
-    .error_for_status()?;
-    let response = flate2::read::GzDecoder::new(response);
-    let dir = std::env::temp_dir().join("conformance-tests");
-    if dir.exists() {
-        if let Some(err) = std::fs::remove_dir_all(&dir).err() {
-            eprintln!("WARNING: download dir {dir:?} already existed and could not be removed. It may contain unexpected test content. {err:?}");
-        }
-    }
-    for entry in tar::Archive::new(response)
-        .entries()
-        .context("failed to read archive")?
-    {
-        let mut entry = entry.context("failed to read archive entry")?;
-        if entry.header().entry_type() != tar::EntryType::Regular {
-            continue;
-        }
-        let path = dir.join(entry.path().context("failed to get archive entry's path")?);
-        let parent_dir = path
-            .parent()
-            .expect("somehow archived file has no parent dir");
-        std::fs::create_dir_all(parent_dir).context("failed to create directory from archive")?;
-        let mut file =
-            std::fs::File::create(&path).context("failed to create file from archive")?;
-        std::io::copy(&mut entry, &mut file).context("failed to copy file from archive")?;
-    }
-    Ok(dir)
+    Config::new(version).download_tests()
 }
 
 /// Read the tests directory and get an iterator to each test's directory
@@ -118,17 +341,15 @@ pub fn tests_iter(tests_dir: impl AsRef<Path>) -> anyhow::Result<impl Iterator<I
                 .and_then(|f| f.to_str())
                 .context("could not determine test name"))
             .to_owned();
-            let config = r#try!(std::fs::read_to_string(test_dir.join("test.json5"))
+            let config = r#try!(config::parse_from_file(test_dir.join("test.json5"))
                 .with_context(|| format!("failed to read test config from {test_dir:?}")));
-            let config = r#try!(json5::from_str::<config::TestConfig>(&config)
-                .context("test config could not be parsed"));
 
-            let component_name = "component.wasm";
+            let components = r#try!(find_components(&test_dir));
             Some(Ok(Test {
                 name,
                 config,
                 manifest: test_dir.join("spin.toml"),
-                component: test_dir.join(component_name),
+                components,
             }))
         })
         .collect::<anyhow::Result<Vec<_>>>()?;
@@ -136,19 +357,67 @@ pub fn tests_iter(tests_dir: impl AsRef<Path>) -> anyhow::Result<impl Iterator<I
     Ok(items.into_iter())
 }
 
+/// Find all the component binaries in a test's directory
+fn find_components(test_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut components = Vec::new();
+    for entry in std::fs::read_dir(test_dir)
+        .with_context(|| format!("failed to read test directory {test_dir:?}"))?
+    {
+        let path = entry?.path();
+        if path.is_file() && path.extension().and_then(|e| e.to_str()) == Some("wasm") {
+            components.push(path);
+        }
+    }
+    components.sort();
+    Ok(components)
+}
+
+/// The file name of the manifest inside of an environment prepared by [`Test::prepare_environment`]
+pub const MANIFEST_FILE_NAME: &str = "spin.toml";
+
 #[derive(Debug, Clone)]
 pub struct Test {
     pub name: String,
     pub config: config::TestConfig,
     pub manifest: PathBuf,
-    pub component: PathBuf,
+    /// The component binaries referenced by the manifest
+    ///
+    /// Each binary is named after the component it was built from (e.g. `request-shape.wasm`)
+    /// and the manifest refers to it by that file name.
+    pub components: Vec<PathBuf>,
+}
+
+impl Test {
+    /// Boot a test environment ready for the runtime to be started in
+    ///
+    /// The services required by the test's preconditions are started, the component binaries
+    /// are copied in and the manifest is written to [`MANIFEST_FILE_NAME`] with all templates
+    /// substituted. The runtime can then be started with [`TestEnvironment::start_runtime`].
+    pub fn prepare_environment(&self) -> anyhow::Result<TestEnvironment<()>> {
+        let mut env = TestEnvironment::boot(self.config.services_config()?)?;
+        for component in &self.components {
+            let file_name = component
+                .file_name()
+                .context("component binary has no file name")?;
+            env.copy_into(component, file_name)?;
+        }
+        let mut manifest = EnvTemplate::from_file(&self.manifest)?;
+        manifest.substitute(&mut env, |_| None)?;
+        env.write_file(MANIFEST_FILE_NAME, manifest.contents())?;
+        Ok(env)
+    }
 }
 
 pub mod assertions {
     use crate::indent_lines;
 
-    use super::config::Response as ExpectedResponse;
-    use test_environment::http::Response as ActualResponse;
+    use super::config::{
+        BodyMatcher, CommandOutput, ExpectedOutput, ExpectedRequest, Method,
+        Response as ExpectedResponse, ResponseHeader,
+    };
+    use anyhow::Context as _;
+    use base64::Engine as _;
+    use test_environment::{http::Response as ActualResponse, services::RecordedRequest};
 
     /// Assert that the actual response matches the expected response
     pub fn assert_response(
@@ -170,54 +439,340 @@ pub mod assertions {
         );
 
         // We assert the body next, because if it's wrong, it usually has more information as to why
-        let expected_body = expected.body.as_deref().unwrap_or_default();
-        let actual_body = actual
-            .text()
-            .unwrap_or_else(|_| String::from("<invalid utf-8>"));
+        match &expected.body {
+            Some(matcher) => assert_body(matcher, &actual.body())?,
+            None => assert_body(&BodyMatcher::Exact(String::new()), &actual.body())?,
+        }
+        if let Some(BodyMatcher::Chunks {
+            chunks,
+            exact_boundaries,
+        }) = &expected.body
+        {
+            assert_chunked(actual)?;
+            if *exact_boundaries {
+                assert_chunk_boundaries(chunks, actual)?;
+            }
+        }
 
+        assert_headers(expected, actual)
+    }
+
+    /// Assert that the output of a command matches the expected output
+    pub fn assert_output(expected: &ExpectedOutput, actual: &CommandOutput) -> anyhow::Result<()> {
+        // We assert the exit code first, because if it's wrong, stderr usually has more information as to why
         anyhow::ensure!(
-            actual_body == expected_body,
-            "actual body != expected body\nactual: {actual_body}\nexpected: {expected_body}",
-            actual_body = indent_lines(&actual_body, 2),
-            expected_body = indent_lines(expected_body, 2)
+            actual.exit_code == expected.exit_code,
+            "actual exit code {} != expected exit code {} - stderr: {}",
+            actual.exit_code,
+            expected.exit_code,
+            indent_lines(&String::from_utf8_lossy(&actual.stderr), 2)
         );
+        if let Some(matcher) = &expected.stdout {
+            assert_body(matcher, &actual.stdout).context("unexpected stdout")?;
+        }
+        if let Some(matcher) = &expected.stderr {
+            assert_body(matcher, &actual.stderr).context("unexpected stderr")?;
+        }
+        Ok(())
+    }
 
-        let mut actual_headers = actual
-            .headers()
-            .iter()
-            .map(|(k, v)| (k.to_lowercase(), v.to_lowercase()))
-            .collect::<std::collections::HashMap<_, _>>();
-        for expected_header in &expected.headers {
-            let expected_name = expected_header.name.to_lowercase();
-            let expected_value = expected_header.value.as_ref().map(|v| v.to_lowercase());
-            let actual_value = actual_headers.remove(&expected_name);
-            let Some(actual_value) = actual_value.as_deref() else {
+    /// Assert that a request recorded by a service matches the expected request
+    ///
+    /// Headers not listed in the expected request are always allowed.
+    pub fn assert_request(
+        expected: &ExpectedRequest,
+        actual: &RecordedRequest,
+    ) -> anyhow::Result<()> {
+        if let Some(method) = &expected.method {
+            anyhow::ensure!(
+                Method::from(actual.method.clone()) == *method,
+                "actual method {} != expected method {method:?}",
+                actual.method
+            );
+        }
+        if let Some(path) = &expected.path {
+            anyhow::ensure!(
+                actual.path == *path,
+                "actual path {} != expected path {path}",
+                actual.path
+            );
+        }
+        if let Some(matcher) = &expected.body {
+            assert_body(matcher, &actual.body)?;
+        }
+        assert_header_list(&expected.headers, &actual.headers, true)
+    }
+
+    /// Assert that the actual headers match the expected headers
+    ///
+    /// Repeated headers are kept as a list of values in the order they were received.
+    pub fn assert_headers(
+        expected: &ExpectedResponse,
+        actual: &ActualResponse,
+    ) -> anyhow::Result<()> {
+        assert_header_list(
+            &expected.headers,
+            actual.headers(),
+            expected.allow_extra_headers,
+        )
+    }
+
+    fn assert_header_list(
+        expected: &[ResponseHeader],
+        actual: &[(String, String)],
+        allow_extra_headers: bool,
+    ) -> anyhow::Result<()> {
+        let mut actual_headers = std::collections::BTreeMap::<_, Vec<_>>::new();
+        for (name, value) in actual {
+            actual_headers
+                .entry(name.to_lowercase())
+                .or_default()
+                .push(value.as_str());
+        }
+        for expected_header in expected {
+            let name = &expected_header.name;
+            let Some(actual_values) = actual_headers.remove(&name.to_lowercase()) else {
                 if expected_header.optional {
                     continue;
                 } else {
-                    anyhow::bail!(
-                        "expected header '{name}' not found in response",
-                        name = expected_header.name
-                    )
+                    anyhow::bail!("expected header '{name}' not found")
                 }
             };
-            if let Some(expected_value) = expected_value {
+            if let Some(expected_value) = &expected_header.value {
+                anyhow::ensure!(
+                    matches!(actual_values.as_slice(), [v] if v.eq_ignore_ascii_case(expected_value)),
+                    "header '{name}' has unexpected value(s) {actual_values:?} != '{expected_value}'"
+                );
+            }
+            if let Some(expected_values) = &expected_header.values {
                 anyhow::ensure!(
-                    actual_value == expected_value,
-                    "header '{name}' has unexpected value '{actual_value}' != '{expected_value}'",
-                    name = expected_header.name
+                    actual_values.len() == expected_values.len()
+                        && actual_values
+                            .iter()
+                            .zip(expected_values)
+                            .all(|(a, e)| a.eq_ignore_ascii_case(e)),
+                    "header '{name}' has unexpected values {actual_values:?} != {expected_values:?}"
                 );
             }
+            if let Some(regex) = &expected_header.regex {
+                let regex = regex::Regex::new(regex)
+                    .with_context(|| format!("invalid regex '{regex}' for header '{name}'"))?;
+                if let Some(value) = actual_values.iter().find(|v| !regex.is_match(v)) {
+                    anyhow::bail!(
+                        "header '{name}' has value '{value}' which does not match regex '{regex}'"
+                    );
+                }
+            }
         }
-        if !actual_headers.is_empty() {
+        if !allow_extra_headers && !actual_headers.is_empty() {
             anyhow::bail!("unexpected headers: {actual_headers:?}");
         }
 
         Ok(())
     }
+
+    /// Assert that the actual body is matched by the body matcher
+    pub fn assert_body(matcher: &BodyMatcher, actual: &[u8]) -> anyhow::Result<()> {
+        let actual_text = || String::from_utf8_lossy(actual);
+        match matcher {
+            BodyMatcher::Exact(expected) => anyhow::ensure!(
+                actual == expected.as_bytes(),
+                "actual body != expected body\nactual: {actual_body}\nexpected: {expected_body}",
+                actual_body = indent_lines(&actual_text(), 2),
+                expected_body = indent_lines(expected, 2)
+            ),
+            BodyMatcher::Regex(regex) => {
+                let regex = regex::Regex::new(regex)
+                    .with_context(|| format!("invalid body regex '{regex}'"))?;
+                let actual_body = std::str::from_utf8(actual).context("body is not valid utf-8")?;
+                anyhow::ensure!(
+                    regex.is_match(actual_body),
+                    "actual body does not match regex '{regex}'\nactual: {actual_body}",
+                    actual_body = indent_lines(actual_body, 2),
+                );
+            }
+            BodyMatcher::Contains(expected) => {
+                let actual_body = std::str::from_utf8(actual).context("body is not valid utf-8")?;
+                anyhow::ensure!(
+                    actual_body.contains(expected.as_str()),
+                    "actual body does not contain '{expected}'\nactual: {actual_body}",
+                    actual_body = indent_lines(actual_body, 2),
+                );
+            }
+            BodyMatcher::Json {
+                value,
+                ignore_paths,
+            } => {
+                let mut actual_json: serde_json::Value = serde_json::from_slice(actual)
+                    .with_context(|| {
+                        format!(
+                            "body is not valid JSON\nactual: {}",
+                            indent_lines(&actual_text(), 2)
+                        )
+                    })?;
+                let mut expected_json = value.clone();
+                for path in ignore_paths {
+                    remove_json_pointer(&mut actual_json, path);
+                    remove_json_pointer(&mut expected_json, path);
+                }
+                anyhow::ensure!(
+                    actual_json == expected_json,
+                    "actual JSON body != expected JSON body\nactual: {actual_body}\nexpected: {expected_body}",
+                    actual_body = indent_lines(&actual_json.to_string(), 2),
+                    expected_body = indent_lines(&expected_json.to_string(), 2)
+                );
+            }
+            BodyMatcher::Bytes(expected) => anyhow::ensure!(
+                actual == expected,
+                "actual body != expected body\nactual: {actual:?}\nexpected: {expected:?}"
+            ),
+            BodyMatcher::Chunks { chunks, .. } => {
+                let expected = chunks.concat();
+                anyhow::ensure!(
+                    actual == expected,
+                    "actual body != expected body\nactual: {actual:?}\nexpected: {expected:?}"
+                );
+            }
+            BodyMatcher::Base64(expected) => {
+                let expected = base64::engine::general_purpose::STANDARD
+                    .decode(expected)
+                    .context("expected body is not valid base64")?;
+                anyhow::ensure!(
+                    actual == expected,
+                    "actual body != expected body\nactual: {}\nexpected: {}",
+                    base64::engine::general_purpose::STANDARD.encode(actual),
+                    base64::engine::general_purpose::STANDARD.encode(&expected)
+                );
+            }
+            BodyMatcher::File(path) => {
+                let expected = std::fs::read(path)
+                    .with_context(|| format!("failed to read expected body from {path:?}"))?;
+                anyhow::ensure!(
+                    actual == expected,
+                    "actual body != expected body in {path:?}\nactual: {actual_body}",
+                    actual_body = indent_lines(&actual_text(), 2),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Assert that the response body was streamed with chunked transfer encoding
+    pub fn assert_chunked(actual: &ActualResponse) -> anyhow::Result<()> {
+        let chunked = actual.header_values("transfer-encoding").any(|v| {
+            v.split(',')
+                .any(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+        });
+        anyhow::ensure!(
+            chunked,
+            "expected the body to be sent with `transfer-encoding: chunked` but it was not"
+        );
+        Ok(())
+    }
+
+    /// Assert that the response body was received in exactly the expected chunks
+    pub fn assert_chunk_boundaries(
+        expected: &[Vec<u8>],
+        actual: &ActualResponse,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            actual.chunks() == expected,
+            "actual chunks != expected chunks\nactual: {:?}\nexpected: {expected:?}",
+            actual.chunks()
+        );
+        Ok(())
+    }
+
+    /// Remove the value at a JSON pointer (e.g. `/items/0/id`) if it exists
+    fn remove_json_pointer(value: &mut serde_json::Value, pointer: &str) {
+        let Some((parent, key)) = pointer.rsplit_once('/') else {
+            return;
+        };
+        let key = key.replace("~1", "/").replace("~0", "~");
+        match value.pointer_mut(parent) {
+            Some(serde_json::Value::Object(map)) => {
+                map.remove(&key);
+            }
+            Some(serde_json::Value::Array(array)) => {
+                if let Ok(index) = key.parse::<usize>() {
+                    if index < array.len() {
+                        // Replace rather than remove so that later indices stay the same
+                        array[index] = serde_json::Value::Null;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A test failure annotated with information for reporting
+///
+/// Test runners can return this (wrapped in an `anyhow::Error`) from the test function to
+/// record which invocation failed and the logs of the runtime and services at the time.
+#[derive(Debug)]
+pub struct TestFailure {
+    /// The underlying error
+    pub error: anyhow::Error,
+    /// The index of the invocation that failed, if any
+    pub invocation: Option<usize>,
+    /// The logs of the runtime and services by their name
+    pub logs: BTreeMap<String, String>,
+}
+
+impl TestFailure {
+    /// Create a failure from an error
+    pub fn new(error: anyhow::Error) -> Self {
+        Self {
+            error,
+            invocation: None,
+            logs: BTreeMap::new(),
+        }
+    }
+
+    /// Record the index of the invocation that failed
+    pub fn invocation(mut self, index: usize) -> Self {
+        self.invocation = Some(index);
+        self
+    }
+
+    /// Record the logs of the runtime or a service
+    pub fn log(mut self, name: impl Into<String>, logs: impl Into<String>) -> Self {
+        self.logs.insert(name.into(), logs.into());
+        self
+    }
+
+    /// Record the logs of all the services
+    pub fn service_logs(mut self, services: &mut test_environment::services::Services) -> Self {
+        for service in services {
+            let logs = service
+                .logs()
+                .unwrap_or_else(|e| format!("<failed to get logs: {e:#}>"));
+            self = self.log(service.name(), logs);
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for TestFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.invocation {
+            Some(index) => write!(f, "invocation {index} failed: {}", self.error),
+            None => self.error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TestFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
 }
 
-/// A wrapper around `anyhow::Error` that prints the full chain of causes
+/// How many lines of each log are shown for a failed test
+const LOG_TAIL_LINES: usize = 20;
+
+/// A wrapper around `anyhow::Error` that prints the full chain of causes and the end of any logs
 struct FullError {
     error: anyhow::Error,
 }
@@ -231,7 +786,20 @@ impl From<anyhow::Error> for FullError {
 impl std::fmt::Display for FullError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", indent_lines(&self.error.to_string(), 2))?;
-        write_error_chain(f, &self.error)?;
+        write_error_chain(&mut *f, &self.error)?;
+        if let Some(failure) = self.error.downcast_ref::<TestFailure>() {
+            for (name, logs) in &failure.logs {
+                let lines = logs.lines().collect::<Vec<_>>();
+                let tail = &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..];
+                write!(f, "\n==> {name} <==")?;
+                if tail.is_empty() {
+                    write!(f, "\n  <no output>")?;
+                }
+                for line in tail {
+                    write!(f, "\n  {line}")?;
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -264,3 +832,297 @@ fn indent_lines(str: &str, indent: usize) -> String {
         .collect::<Vec<_>>()
         .join("\n")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(config: &str) -> config::TestConfig {
+        config::parse(config).unwrap()
+    }
+
+    #[test]
+    fn invalid_capabilities_are_rejected_when_configured() {
+        assert!(Config::new("local")
+            .capabilities(["wasi:http@0.2"])
+            .is_err());
+        assert!(Config::new("local").capabilities(["@1.0.0"]).is_err());
+        assert!(Config::new("local").spin_version("3").is_err());
+    }
+
+    #[test]
+    fn tests_needing_missing_capabilities_are_skipped() {
+        let config = Config::new("local")
+            .capabilities(["wasi:http@0.2.1", "spin:postgres@3.0.0"])
+            .unwrap()
+            .spin_version("3.0.0")
+            .unwrap();
+        let skip_reason = |test: &str| config.skip_reason(&test_config(test));
+        assert_eq!(skip_reason(r#"{ requires: ["wasi:http@0.2.0"] }"#), None);
+        assert_eq!(
+            skip_reason(r#"{ requires: ["spin:postgres@4.0.0", "spin:mqtt"] }"#),
+            Some("requires spin:postgres@4.0.0, spin:mqtt".into())
+        );
+        assert_eq!(
+            skip_reason(r#"{ "min-spin-version": "3.1.0" }"#),
+            Some("requires Spin 3.1.0".into())
+        );
+        // Without declared capabilities every capability is assumed to be supported
+        let config = Config::new("local");
+        assert_eq!(
+            config.skip_reason(&test_config(r#"{ requires: ["spin:mqtt"] }"#)),
+            None
+        );
+    }
+
+    #[test]
+    fn tests_find_all_their_components() {
+        let dir = tempfile::tempdir().unwrap();
+        let test_dir = dir.path().join("multi-component");
+        std::fs::create_dir_all(test_dir.join("assets")).unwrap();
+        std::fs::write(test_dir.join("test.json5"), "{}").unwrap();
+        std::fs::write(test_dir.join("spin.toml"), "").unwrap();
+        for file in [
+            "frontend.wasm",
+            "backend.wasm",
+            "body.txt",
+            "assets/nested.wasm",
+        ] {
+            std::fs::write(test_dir.join(file), "").unwrap();
+        }
+        // Files next to the tests aren't tests
+        std::fs::write(dir.path().join("index.json"), "{}").unwrap();
+
+        let tests = tests_iter(dir.path()).unwrap().collect::<Vec<_>>();
+        assert_eq!(tests.len(), 1);
+        assert_eq!(tests[0].name, "multi-component");
+        assert_eq!(tests[0].manifest, test_dir.join("spin.toml"));
+        assert_eq!(
+            tests[0].components,
+            [
+                test_dir.join("backend.wasm"),
+                test_dir.join("frontend.wasm")
+            ]
+        );
+    }
+
+    /// Write empty tests with the given names into a temporary tests directory
+    fn tests_dir(names: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for name in names {
+            let test_dir = dir.path().join(name);
+            std::fs::create_dir(&test_dir).unwrap();
+            std::fs::write(test_dir.join("test.json5"), "{}").unwrap();
+            std::fs::write(test_dir.join("spin.toml"), "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn expected_failures_pass_when_failing_and_fail_when_passing() {
+        let dir = tests_dir(&["fails", "passes"]);
+        let report_path = dir.path().join("report.json");
+        let config = Config::new("local")
+            .expect_failure("fails", "known bug")
+            .expect_failure("passes", "known bug")
+            .report(ReportFormat::Json, &report_path);
+        let conclusion = run_tests_from(dir.path(), config, |test| {
+            if test.name == "fails" {
+                anyhow::bail!("broken")
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(conclusion.num_passed, 1);
+        assert_eq!(conclusion.num_failed, 1);
+
+        let report: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+        let tests = report["tests"].as_array().unwrap();
+        assert_eq!(tests[0]["name"], "fails");
+        assert_eq!(tests[0]["outcome"], "expected-failure");
+        assert_eq!(tests[0]["reason"], "known bug");
+        assert_eq!(tests[0]["error"], serde_json::json!(["broken"]));
+        assert_eq!(tests[1]["name"], "passes");
+        assert_eq!(tests[1]["outcome"], "failed");
+        assert_eq!(
+            tests[1]["error"],
+            serde_json::json!([
+                "unexpected pass: test was expected to fail (known bug) but it passed"
+            ])
+        );
+    }
+
+    #[test]
+    fn trials_are_selected_like_the_harness_does() {
+        let trial =
+            |name: &str, kind: &str| libtest_mimic::Trial::test(name, || Ok(())).with_kind(kind);
+        let args = |args: &[&str]| {
+            libtest_mimic::Arguments::from_iter(std::iter::once("test").chain(args.iter().copied()))
+        };
+        assert!(is_selected(&args(&[]), &trial("redis-trigger", "")));
+        assert!(is_selected(&args(&["redis"]), &trial("redis-trigger", "")));
+        assert!(!is_selected(
+            &args(&["sqlite"]),
+            &trial("redis-trigger", "")
+        ));
+        assert!(!is_selected(
+            &args(&["--exact", "redis"]),
+            &trial("redis-trigger", "")
+        ));
+        assert!(is_selected(
+            &args(&["xfail"]),
+            &trial("redis-trigger", "xfail")
+        ));
+        assert!(!is_selected(
+            &args(&["--skip", "trigger"]),
+            &trial("redis-trigger", "")
+        ));
+    }
+
+    fn assert_body(matcher: serde_json::Value, actual: &str) -> anyhow::Result<()> {
+        let matcher: config::BodyMatcher = serde_json::from_value(matcher).unwrap();
+        assertions::assert_body(&matcher, actual.as_bytes())
+    }
+
+    #[test]
+    fn body_regex_and_contains() {
+        use serde_json::json;
+        assert_body(json!({ "regex": "^id-[0-9]+$" }), "id-42").unwrap();
+        assert!(assert_body(json!({ "regex": "^id-[0-9]+$" }), "id-4x").is_err());
+        assert!(assert_body(json!({ "regex": "(" }), "(").is_err());
+        assert_body(json!({ "contains": "world" }), "hello world!").unwrap();
+        assert!(assert_body(json!({ "contains": "world" }), "hello").is_err());
+    }
+
+    #[test]
+    fn body_json_ignores_paths() {
+        use serde_json::json;
+        let matcher = |ignore_paths: &[&str]| {
+            json!({
+                "json": {
+                    "value": { "items": [{ "id": 1, "name": "a" }, { "id": 2, "name": "b" }], "a/b": 1 },
+                    "ignore-paths": ignore_paths,
+                }
+            })
+        };
+        let actual =
+            r#"{ "a/b": 1, "items": [{ "name": "a", "id": 7 }, { "id": 2, "name": "b" }] }"#;
+        assert!(assert_body(matcher(&[]), actual).is_err());
+        assert_body(matcher(&["/items/0/id"]), actual).unwrap();
+        // An ignored array element is still compared positionally with the rest
+        assert_body(matcher(&["/items/0"]), actual).unwrap();
+        assert!(assert_body(matcher(&["/items/1"]), actual).is_err());
+        assert!(assert_body(matcher(&["/items/0/id"]), "not json").is_err());
+
+        let actual =
+            r#"{ "a/b": 2, "items": [{ "id": 1, "name": "a" }, { "id": 2, "name": "b" }] }"#;
+        assert!(assert_body(matcher(&["/a/b"]), actual).is_err());
+        assert_body(matcher(&["/a~1b"]), actual).unwrap();
+    }
+
+    #[test]
+    fn body_bytes_and_base64() {
+        use serde_json::json;
+        assert_body(json!({ "bytes": [104, 105] }), "hi").unwrap();
+        assert!(assert_body(json!({ "bytes": [104, 105] }), "ho").is_err());
+        assert_body(json!({ "base64": "aGk=" }), "hi").unwrap();
+        assert!(assert_body(json!({ "base64": "aGk=" }), "ho").is_err());
+        assert!(assert_body(json!({ "base64": "not base64!" }), "hi").is_err());
+    }
+
+    #[test]
+    fn chunked_bodies_only_check_boundaries_when_asked() {
+        let response = |body: &str| -> config::Response {
+            json5::from_str(&format!(
+                r#"{{ headers: [{{ name: "transfer-encoding", value: "chunked" }}], body: {body} }}"#
+            ))
+            .unwrap()
+        };
+        let actual = |chunks: Vec<Vec<u8>>| {
+            test_environment::http::Response::full(
+                200,
+                [("transfer-encoding".into(), "chunked".into())],
+                chunks,
+            )
+        };
+        let split = actual(vec![b"he".to_vec(), b"llo".to_vec()]);
+        let whole = actual(vec![b"hello".to_vec()]);
+
+        for body in [
+            "[[104, 101], [108, 108, 111]]",
+            "{ chunks: [[104, 101], [108, 108, 111]] }",
+        ] {
+            assertions::assert_response(&response(body), &split).unwrap();
+            assertions::assert_response(&response(body), &whole).unwrap();
+        }
+        let exact =
+            response("{ chunks: [[104, 101], [108, 108, 111]], \"exact-boundaries\": true }");
+        assertions::assert_response(&exact, &split).unwrap();
+        assert!(assertions::assert_response(&exact, &whole).is_err());
+
+        let not_chunked = test_environment::http::Response::new_with_body(200, "hello");
+        assert!(assertions::assert_response(
+            &response("{ chunks: [[104, 101, 108, 108, 111]] }"),
+            &not_chunked
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn response_headers_are_matched() {
+        let assert_headers = |headers: &str, actual: &[(&str, &str)]| {
+            let expected: config::Response =
+                json5::from_str(&format!("{{ headers: {headers}, body: \"\" }}")).unwrap();
+            let actual = test_environment::http::Response::full(
+                200,
+                actual.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+                "",
+            );
+            assertions::assert_headers(&expected, &actual)
+        };
+        let exact = r#"[{ name: "Content-Type", value: "text/plain" }]"#;
+        assert_headers(exact, &[("content-type", "TEXT/Plain")]).unwrap();
+        assert!(assert_headers(exact, &[("content-type", "text/html")]).is_err());
+        assert!(assert_headers(exact, &[]).is_err());
+        // A single value doesn't match a repeated header
+        assert!(assert_headers(
+            exact,
+            &[
+                ("content-type", "text/plain"),
+                ("Content-Type", "text/plain")
+            ]
+        )
+        .is_err());
+
+        let values = r#"[{ name: "set-cookie", values: ["a=1", "b=2"] }]"#;
+        assert_headers(values, &[("Set-Cookie", "a=1"), ("set-cookie", "b=2")]).unwrap();
+        assert!(assert_headers(values, &[("set-cookie", "b=2"), ("set-cookie", "a=1")]).is_err());
+        assert!(assert_headers(values, &[("set-cookie", "a=1")]).is_err());
+
+        let regex = r#"[{ name: "x-id", regex: "^[0-9]+$" }]"#;
+        assert_headers(regex, &[("x-id", "1"), ("x-id", "23")]).unwrap();
+        assert!(assert_headers(regex, &[("x-id", "1"), ("x-id", "b")]).is_err());
+        assert!(assert_headers(r#"[{ name: "x-id", regex: "(" }]"#, &[("x-id", "1")]).is_err());
+
+        let optional = r#"[{ name: "date", optional: true }]"#;
+        assert_headers(optional, &[]).unwrap();
+        assert_headers(optional, &[("date", "today")]).unwrap();
+
+        assert!(assert_headers("[]", &[("x-extra", "1")]).is_err());
+        let allow_extra = |headers: &str| {
+            let expected: config::Response = json5::from_str(&format!(
+                "{{ headers: {headers}, body: \"\", \"allow-extra-headers\": true }}"
+            ))
+            .unwrap();
+            let actual = test_environment::http::Response::full(
+                200,
+                [("x-extra".to_owned(), "1".to_owned())],
+                "",
+            );
+            assertions::assert_headers(&expected, &actual)
+        };
+        allow_extra("[]").unwrap();
+        assert!(allow_extra(exact).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! A minimal MQTT 3.1.1 client for observing the messages published to the MQTT service
+
+use anyhow::Context as _;
+use std::{
+    io::{Read, Write as _},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+
+/// A subscription to a topic which collects the messages published to it in the background
+pub(crate) struct Subscription {
+    stream: TcpStream,
+    messages: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Subscription {
+    /// Connect to the MQTT broker at the given host and port and subscribe to the topic
+    pub fn subscribe(host: &str, port: u16, topic: &str) -> anyhow::Result<Self> {
+        let mut stream = TcpStream::connect((host, port))
+            .with_context(|| format!("failed to connect to mqtt broker at {host}:{port}"))?;
+
+        let mut connect = Vec::new();
+        write_string(&mut connect, "MQTT");
+        // Protocol level 4 (i.e. 3.1.1), a clean session and a keep alive of 60 seconds
+        connect.extend([4, 0x02, 0, 60]);
+        write_string(
+            &mut connect,
+            &format!("conformance-tests-{}", std::process::id()),
+        );
+        write_packet(&mut stream, CONNECT, &connect)?;
+        let (kind, connack) = read_packet(&mut stream)?;
+        anyhow::ensure!(
+            kind == CONNACK && connack.get(1) == Some(&0),
+            "mqtt broker refused the connection"
+        );
+
+        let mut subscribe = vec![0, 1];
+        write_string(&mut subscribe, topic);
+        // At most once delivery is enough as the broker is local
+        subscribe.push(0);
+        write_packet(&mut stream, SUBSCRIBE, &subscribe)?;
+        let (kind, suback) = read_packet(&mut stream)?;
+        anyhow::ensure!(
+            kind == SUBACK && suback.get(2).is_some_and(|&code| code != 0x80),
+            "mqtt broker refused the subscription to topic '{topic}'"
+        );
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = stream.try_clone()?;
+        let received = messages.clone();
+        std::thread::spawn(move || {
+            // Reading stops once the connection is shut down
+            while let Ok((kind, packet)) = read_packet(&mut reader) {
+                if kind & 0xf0 != PUBLISH {
+                    continue;
+                }
+                let Some(topic_length) = packet.get(..2) else {
+                    continue;
+                };
+                let mut offset =
+                    2 + u16::from_be_bytes([topic_length[0], topic_length[1]]) as usize;
+                // Messages with a QoS above 0 carry a packet identifier
+                if kind & 0x06 != 0 {
+                    offset += 2;
+                }
+                if let Some(payload) = packet.get(offset..) {
+                    received.lock().unwrap().push(payload.to_vec());
+                }
+            }
+        });
+        Ok(Self { stream, messages })
+    }
+
+    /// The messages received so far
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, s: &str) {
+    buffer.extend((s.len() as u16).to_be_bytes());
+    buffer.extend(s.as_bytes());
+}
+
+fn write_packet(stream: &mut TcpStream, kind: u8, body: &[u8]) -> anyhow::Result<()> {
+    let mut packet = vec![kind];
+    // The remaining length is encoded 7 bits at a time
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    stream
+        .write_all(&packet)
+        .context("failed to send mqtt packet")
+}
+
+fn read_packet(stream: &mut impl Read) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut byte = [0];
+    stream.read_exact(&mut byte)?;
+    let kind = byte[0];
+    let mut length = 0;
+    let mut multiplier = 1;
+    loop {
+        stream.read_exact(&mut byte)?;
+        length += (byte[0] & 0x7f) as usize * multiplier;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        multiplier *= 128;
+        anyhow::ensure!(
+            multiplier <= 128 * 128 * 128,
+            "malformed mqtt packet length"
+        );
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body)?;
+    Ok((kind, body))
+}
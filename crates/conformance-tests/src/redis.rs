@@ -0,0 +1,106 @@
+//! A minimal Redis client for driving and observing the Redis service
+
+use anyhow::Context as _;
+use std::{
+    io::{BufRead as _, BufReader, Read as _, Write as _},
+    net::TcpStream,
+};
+
+/// A connection to a Redis server speaking RESP
+pub(crate) struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+/// A reply from the Redis server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    Nil,
+    Status(String),
+    Int(i64),
+    Bytes(Vec<u8>),
+    Array(Vec<Value>),
+}
+
+impl Connection {
+    /// Connect to the Redis server at the given host and port
+    pub fn open(host: &str, port: u16) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect((host, port))
+            .with_context(|| format!("failed to connect to redis at {host}:{port}"))?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Run a command and return its reply
+    pub fn command(&mut self, args: &[&[u8]]) -> anyhow::Result<Value> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend(*arg);
+            request.extend(b"\r\n");
+        }
+        self.stream
+            .get_mut()
+            .write_all(&request)
+            .context("failed to send redis command")?;
+        self.read_value()
+    }
+
+    /// Publish a message to a channel returning the number of subscribers that received it
+    pub fn publish(&mut self, channel: &str, message: &[u8]) -> anyhow::Result<i64> {
+        match self.command(&[b"PUBLISH", channel.as_bytes(), message])? {
+            Value::Int(receivers) => Ok(receivers),
+            value => anyhow::bail!("unexpected reply to PUBLISH: {value:?}"),
+        }
+    }
+
+    /// Get the value of a key
+    pub fn get(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.command(&[b"GET", key.as_bytes()])? {
+            Value::Nil => Ok(None),
+            Value::Bytes(value) => Ok(Some(value)),
+            value => anyhow::bail!("unexpected reply to GET: {value:?}"),
+        }
+    }
+
+    fn read_value(&mut self) -> anyhow::Result<Value> {
+        let mut line = String::new();
+        self.stream
+            .read_line(&mut line)
+            .context("failed to read redis reply")?;
+        let line = line.trim_end_matches("\r\n");
+        anyhow::ensure!(!line.is_empty(), "redis closed the connection");
+        let (kind, rest) = line.split_at(1);
+        let length = || -> anyhow::Result<i64> {
+            rest.parse()
+                .with_context(|| format!("malformed redis reply '{line}'"))
+        };
+        match kind {
+            "+" => Ok(Value::Status(rest.to_owned())),
+            "-" => anyhow::bail!("redis replied with an error: {rest}"),
+            ":" => Ok(Value::Int(length()?)),
+            "$" => {
+                let Ok(length) = usize::try_from(length()?) else {
+                    return Ok(Value::Nil);
+                };
+                // The bulk string is followed by a trailing CRLF
+                let mut bytes = vec![0; length + 2];
+                self.stream
+                    .read_exact(&mut bytes)
+                    .context("failed to read redis reply")?;
+                bytes.truncate(length);
+                Ok(Value::Bytes(bytes))
+            }
+            "*" => {
+                let Ok(length) = usize::try_from(length()?) else {
+                    return Ok(Value::Nil);
+                };
+                let values = (0..length)
+                    .map(|_| self.read_value())
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Value::Array(values))
+            }
+            _ => anyhow::bail!("malformed redis reply '{line}'"),
+        }
+    }
+}
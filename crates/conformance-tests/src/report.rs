@@ -0,0 +1,290 @@
+//! Machine readable reports of a conformance test run
+
+use anyhow::Context as _;
+use std::{collections::BTreeMap, fmt::Write as _, path::Path, time::Duration};
+
+/// The format a report is written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// JUnit XML as understood by most CI dashboards
+    JUnit,
+    /// JSON serialization of the [`Report`]
+    Json,
+}
+
+/// The results of all the tests that were run
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Report {
+    pub tests: Vec<TestReport>,
+}
+
+/// The result of a single test
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TestReport {
+    pub name: String,
+    pub outcome: Outcome,
+    /// Why the test was ignored or expected to fail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// How long the test took to run in seconds
+    pub duration: f64,
+    /// The index of the invocation that failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_invocation: Option<usize>,
+    /// The error followed by all of its causes
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub error: Vec<String>,
+    /// Logs captured from the runtime and services by their name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub logs: BTreeMap<String, String>,
+}
+
+impl TestReport {
+    pub(crate) fn new(name: impl Into<String>, outcome: Outcome, duration: Duration) -> Self {
+        Self {
+            name: name.into(),
+            outcome,
+            reason: None,
+            duration: duration.as_secs_f64(),
+            failed_invocation: None,
+            error: Vec::new(),
+            logs: BTreeMap::new(),
+        }
+    }
+
+    /// Record the details of a test failure
+    pub(crate) fn with_failure(mut self, error: &anyhow::Error) -> Self {
+        self.error = error.chain().map(|e| e.to_string()).collect();
+        if let Some(failure) = error.downcast_ref::<crate::TestFailure>() {
+            self.failed_invocation = failure.invocation;
+            self.logs = failure.logs.clone();
+        }
+        self
+    }
+}
+
+/// The outcome of a test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Passed,
+    Failed,
+    Ignored,
+    /// The test failed as expected
+    ExpectedFailure,
+}
+
+impl Report {
+    /// Write the report to a file in the given format
+    pub fn write(&self, format: ReportFormat, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let contents = match format {
+            ReportFormat::JUnit => self.to_junit_xml(),
+            ReportFormat::Json => self.to_json()?,
+        };
+        std::fs::write(path, contents).with_context(|| format!("failed to write report {path:?}"))
+    }
+
+    /// Serialize the report as JSON
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        serde_json::to_string_pretty(self).context("failed to serialize report")
+    }
+
+    /// Serialize the report as JUnit XML
+    pub fn to_junit_xml(&self) -> String {
+        let count = |outcome| self.tests.iter().filter(|t| t.outcome == outcome).count();
+        let time: f64 = self.tests.iter().map(|t| t.duration).sum();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let attributes = format!(
+            "name=\"conformance-tests\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{time:.3}\"",
+            self.tests.len(),
+            count(Outcome::Failed),
+            count(Outcome::Ignored),
+        );
+        let _ = writeln!(xml, "<testsuites {attributes}>");
+        let _ = writeln!(xml, "  <testsuite {attributes}>");
+        for test in &self.tests {
+            let mut body = String::new();
+            match test.outcome {
+                Outcome::Passed | Outcome::ExpectedFailure => {}
+                Outcome::Ignored => {
+                    let message = test.reason.as_deref().unwrap_or_default();
+                    let _ = write!(body, "\n      <skipped message=\"{}\"/>", escape(message));
+                }
+                Outcome::Failed => {
+                    let message = test.error.first().map(String::as_str).unwrap_or_default();
+                    let details = test.error.join("\nCaused by: ");
+                    let _ = write!(
+                        body,
+                        "\n      <failure message=\"{}\">{}</failure>",
+                        escape(message),
+                        escape(&details)
+                    );
+                }
+            }
+            if !test.logs.is_empty() {
+                let logs = test
+                    .logs
+                    .iter()
+                    .map(|(name, logs)| format!("==> {name} <==\n{logs}"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let _ = write!(body, "\n      <system-out>{}</system-out>", escape(&logs));
+            }
+            let _ = write!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"conformance-tests\" time=\"{:.3}\"",
+                escape(&test.name),
+                test.duration
+            );
+            if body.is_empty() {
+                xml.push_str("/>\n");
+            } else {
+                let _ = writeln!(xml, ">{body}\n    </testcase>");
+            }
+        }
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+}
+
+/// Escape a string for use in XML text and attributes
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Control characters other than whitespace are not allowed in XML
+            c if c.is_control() && !matches!(c, '\n' | '\r' | '\t') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_report(name: &str, outcome: Outcome) -> TestReport {
+        TestReport::new(name, outcome, Duration::from_millis(1500))
+    }
+
+    fn report() -> Report {
+        let mut ignored = test_report("ignored", Outcome::Ignored);
+        ignored.reason = Some("requires <spin:mqtt> & \"more\"".into());
+        let mut failed = test_report("fails<&>", Outcome::Failed);
+        failed.error = vec!["invocation 0 failed".into(), "status 500 != 200".into()];
+        failed.failed_invocation = Some(0);
+        failed.logs = BTreeMap::from([("runtime".into(), "<error> it's\u{1b}[31m bad".into())]);
+        let mut expected_failure = test_report("xfail", Outcome::ExpectedFailure);
+        expected_failure.reason = Some("known bug".into());
+        expected_failure.error = vec!["broken".into()];
+        Report {
+            tests: vec![
+                test_report("passes", Outcome::Passed),
+                ignored,
+                failed,
+                expected_failure,
+            ],
+        }
+    }
+
+    #[test]
+    fn escape_replaces_markup_and_drops_control_characters() {
+        assert_eq!(
+            escape("<a href=\"x\">'b' & c</a>\u{0}\u{1b}\n\t"),
+            "&lt;a href=&quot;x&quot;&gt;&apos;b&apos; &amp; c&lt;/a&gt;\n\t"
+        );
+    }
+
+    #[test]
+    fn junit_counts_failures_and_skipped() {
+        let xml = report().to_junit_xml();
+        assert!(
+            xml.contains(
+                "<testsuite name=\"conformance-tests\" tests=\"4\" failures=\"1\" skipped=\"1\" time=\"6.000\">"
+            ),
+            "{xml}"
+        );
+    }
+
+    #[test]
+    fn junit_escapes_names_reasons_and_logs() {
+        let xml = report().to_junit_xml();
+        assert!(
+            xml.contains(
+                "<testcase name=\"passes\" classname=\"conformance-tests\" time=\"1.500\"/>"
+            ),
+            "{xml}"
+        );
+        assert!(
+            xml.contains(
+                "<skipped message=\"requires &lt;spin:mqtt&gt; &amp; &quot;more&quot;\"/>"
+            ),
+            "{xml}"
+        );
+        assert!(
+            xml.contains("<testcase name=\"fails&lt;&amp;&gt;\""),
+            "{xml}"
+        );
+        assert!(
+            xml.contains(
+                "<failure message=\"invocation 0 failed\">invocation 0 failed\nCaused by: status 500 != 200</failure>"
+            ),
+            "{xml}"
+        );
+        assert!(
+            xml.contains(
+                "<system-out>==&gt; runtime &lt;==\n&lt;error&gt; it&apos;s[31m bad</system-out>"
+            ),
+            "{xml}"
+        );
+        // Expected failures are reported as passing
+        assert!(
+            xml.contains(
+                "<testcase name=\"xfail\" classname=\"conformance-tests\" time=\"1.500\"/>"
+            ),
+            "{xml}"
+        );
+    }
+
+    #[test]
+    fn json_has_outcome_specific_fields() {
+        let json: serde_json::Value = serde_json::from_str(&report().to_json().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "tests": [
+                    { "name": "passes", "outcome": "passed", "duration": 1.5 },
+                    {
+                        "name": "ignored",
+                        "outcome": "ignored",
+                        "reason": "requires <spin:mqtt> & \"more\"",
+                        "duration": 1.5
+                    },
+                    {
+                        "name": "fails<&>",
+                        "outcome": "failed",
+                        "duration": 1.5,
+                        "failed-invocation": 0,
+                        "error": ["invocation 0 failed", "status 500 != 200"],
+                        "logs": { "runtime": "<error> it's\u{1b}[31m bad" }
+                    },
+                    {
+                        "name": "xfail",
+                        "outcome": "expected-failure",
+                        "reason": "known bug",
+                        "duration": 1.5,
+                        "error": ["broken"]
+                    }
+                ]
+            })
+        );
+    }
+}
@@ -30,6 +30,48 @@ macro_rules! gen_http_trigger_bindings {
     };
 }
 
+/// Generate bindings for the redis-trigger world.
+#[macro_export]
+macro_rules! gen_redis_trigger_bindings {
+    ($ident:ident) => {
+        mod bindings {
+            wit_bindgen::generate!({
+                 world: "redis-trigger",
+                 path:  "../../wit",
+                 with: {
+                     "wasi:http/types@0.2.0": helper::bindings::wasi::http0_2_0::types,
+                     "wasi:http/outgoing-handler@0.2.0": helper::bindings::wasi::http0_2_0::outgoing_handler,
+                 }
+            });
+            use super::$ident;
+            export!($ident);
+
+            pub use exports::fermyon::spin::inbound_redis::{Error, Guest, Payload};
+        }
+    };
+}
+
+/// Generate bindings for the command world.
+#[macro_export]
+macro_rules! gen_command_bindings {
+    ($ident:ident) => {
+        mod bindings {
+            wit_bindgen::generate!({
+                 world: "command",
+                 path:  "../../wit",
+                 with: {
+                     "wasi:http/types@0.2.0": helper::bindings::wasi::http0_2_0::types,
+                     "wasi:http/outgoing-handler@0.2.0": helper::bindings::wasi::http0_2_0::outgoing_handler,
+                 }
+            });
+            use super::$ident;
+            export!($ident);
+
+            pub use exports::wasi::cli0_2_0::run::Guest;
+        }
+    };
+}
+
 use bindings::wasi::http0_2_0::types::{
     Headers, IncomingRequest, OutgoingBody, OutgoingResponse, ResponseOutparam,
 };
@@ -0,0 +1,91 @@
+//! A stub runtime which answers every HTTP request with an empty `200 OK` response
+//!
+//! Apps whose manifest has a command trigger are instead run by echoing their arguments and
+//! stdin to stdout and their environment (as set with `--env`) to stderr.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut listen = String::from("127.0.0.1:3000");
+    let mut env = Vec::new();
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => listen = args.next().expect("--listen requires an address"),
+            "--env" => env.push(args.next().expect("--env requires a NAME=VALUE pair")),
+            _ => positional.push(arg),
+        }
+    }
+    // The first positional argument is the manifest and the rest are the app's arguments
+    let is_command = positional.first().is_some_and(|manifest| {
+        std::fs::read_to_string(manifest).is_ok_and(|m| m.contains("[[trigger.command]]"))
+    });
+    if is_command {
+        run_command(&positional[1..], &env);
+    }
+    let listener = TcpListener::bind(&listen).expect("failed to bind to listen address");
+    println!("Serving http://{listen}");
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        std::thread::spawn(move || {
+            if let Err(e) = handle(stream) {
+                eprintln!("Error handling connection: {e}");
+            }
+        });
+    }
+}
+
+/// Run a command app and exit with its exit code
+fn run_command(args: &[String], env: &[String]) -> ! {
+    let mut stdin = String::new();
+    std::io::stdin()
+        .read_to_string(&mut stdin)
+        .expect("failed to read stdin");
+    println!("args: {}\nstdin: {stdin}", args.join(" "));
+    for var in env {
+        eprintln!("{var}");
+    }
+    std::process::exit(
+        if env.iter().any(|var| var.starts_with("EXIT_WITH_ERROR=")) {
+            1
+        } else {
+            0
+        },
+    )
+}
+
+/// Handle all the requests sent on a connection
+fn handle(stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    loop {
+        let mut content_length = 0;
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        // Drain the body so the next request on the connection can be read
+        std::io::copy(
+            &mut (&mut reader).take(content_length),
+            &mut std::io::sink(),
+        )?;
+        stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")?;
+    }
+}
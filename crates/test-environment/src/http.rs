@@ -1,7 +1,5 @@
 //! Utilities for tests that function over HTTP
 
-use std::collections::HashMap;
-
 use anyhow::Context as _;
 
 /// A request to the Spin server
@@ -13,7 +11,7 @@ pub struct Request<'a, B> {
     pub body: Option<B>,
 }
 
-impl<'a, 'b> Request<'a, &'b [u8]> {
+impl<'a> Request<'a, &[u8]> {
     /// Create a new request with no headers or body
     pub fn new(method: Method, uri: &'a str) -> Self {
         Self {
@@ -46,7 +44,7 @@ impl<'a, B: Into<reqwest::Body>> Request<'a, B> {
     /// Send the request to the given host and port
     pub fn send(self, host: &str, port: u16) -> anyhow::Result<Response> {
         let mut outgoing = reqwest::Request::new(
-            self.method.into(),
+            self.method.try_into()?,
             reqwest::Url::parse(&format!("http://{host}:{port}"))
                 .unwrap()
                 .join(self.path)
@@ -93,38 +91,73 @@ impl<'a, B: Into<reqwest::Body>> Request<'a, B> {
                             v.to_str().unwrap_or("<non-utf8>").to_owned(),
                         )
                     })
-                    .collect(),
+                    .collect::<Vec<_>>(),
                 chunks,
             ))
         })
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// The body of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    /// A body sent all at once with a `content-length`
+    Full(Vec<u8>),
+    /// A body sent as a streaming upload, one chunk at a time
+    Chunked(Vec<Vec<u8>>),
+}
+
+impl From<Body> for reqwest::Body {
+    fn from(body: Body) -> Self {
+        match body {
+            Body::Full(bytes) => bytes.into(),
+            Body::Chunked(chunks) => reqwest::Body::wrap_stream(futures_util::stream::iter(
+                chunks.into_iter().map(Ok::<_, std::convert::Infallible>),
+            )),
+        }
+    }
+}
+
+/// The method of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Method {
     Get,
+    Head,
     Post,
     Put,
     Patch,
     Delete,
+    Options,
+    Trace,
+    Connect,
+    /// An extension method (e.g. `PURGE`)
+    Custom(String),
 }
 
-impl From<Method> for reqwest::Method {
-    fn from(method: Method) -> Self {
-        match method {
+impl TryFrom<Method> for reqwest::Method {
+    type Error = anyhow::Error;
+
+    fn try_from(method: Method) -> anyhow::Result<Self> {
+        Ok(match method {
             Method::Get => reqwest::Method::GET,
+            Method::Head => reqwest::Method::HEAD,
             Method::Post => reqwest::Method::POST,
             Method::Put => reqwest::Method::PUT,
             Method::Patch => reqwest::Method::PATCH,
             Method::Delete => reqwest::Method::DELETE,
-        }
+            Method::Options => reqwest::Method::OPTIONS,
+            Method::Trace => reqwest::Method::TRACE,
+            Method::Connect => reqwest::Method::CONNECT,
+            Method::Custom(method) => reqwest::Method::from_bytes(method.as_bytes())
+                .with_context(|| format!("invalid HTTP method '{method}'"))?,
+        })
     }
 }
 
 /// A response from a Spin server
 pub struct Response {
     status: u16,
-    headers: HashMap<String, String>,
+    headers: Vec<(String, String)>,
     chunks: Vec<Vec<u8>>,
 }
 
@@ -148,10 +181,16 @@ impl Response {
     }
 
     /// A response with headers and a body
-    pub fn full(status: u16, headers: HashMap<String, String>, chunks: impl IntoChunks) -> Self {
+    ///
+    /// Headers may be repeated in which case every value is kept in order.
+    pub fn full(
+        status: u16,
+        headers: impl IntoIterator<Item = (String, String)>,
+        chunks: impl IntoChunks,
+    ) -> Self {
         Self {
             status,
-            headers,
+            headers: headers.into_iter().collect(),
             chunks: chunks.into_chunks(),
         }
     }
@@ -161,11 +200,19 @@ impl Response {
         self.status
     }
 
-    /// The headers of the response
-    pub fn headers(&self) -> &HashMap<String, String> {
+    /// The headers of the response including every value of repeated headers
+    pub fn headers(&self) -> &[(String, String)] {
         &self.headers
     }
 
+    /// All the values of the header with the given (case-insensitive) name
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
     /// The body of the response
     pub fn body(&self) -> Vec<u8> {
         self.chunks.iter().flatten().copied().collect()
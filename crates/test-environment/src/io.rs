@@ -35,6 +35,21 @@ impl OutputStream {
         &self.buffer
     }
 
+    /// Wait for the stream to be closed (e.g. because the process exited) and get all of its output
+    ///
+    /// Gives up waiting after `timeout` and returns the output so far.
+    pub fn output_until_closed(&mut self, timeout: std::time::Duration) -> &[u8] {
+        let deadline = std::time::Instant::now() + timeout;
+        while let Some(remaining) = deadline.checked_duration_since(std::time::Instant::now()) {
+            match self.rx.recv_timeout(remaining) {
+                // An empty read means the end of the stream has been reached
+                Ok(Ok(chunk)) if !chunk.is_empty() => self.buffer.extend(chunk),
+                _ => break,
+            }
+        }
+        &self.buffer
+    }
+
     /// Get the output of the stream so far
     ///
     /// Returns None if the output is not valid utf8
@@ -8,6 +8,29 @@ pub mod test_environment;
 pub trait Runtime {
     /// Return an error if the runtime has errored
     fn error(&mut self) -> anyhow::Result<()>;
+
+    /// Block until the runtime has started and return its error output if it refused to start
+    ///
+    /// Unlike [`Runtime::error`], this is about the runtime rejecting the app up front (e.g.
+    /// because of an invalid manifest) and not about it failing once it's running. Returns
+    /// `None` if the runtime started or can't tell.
+    fn startup_failure(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// An optional runtime, e.g. for tests that only sometimes need a long running runtime
+impl<R: Runtime> Runtime for Option<R> {
+    fn error(&mut self) -> anyhow::Result<()> {
+        match self {
+            Some(runtime) => runtime.error(),
+            None => Ok(()),
+        }
+    }
+
+    fn startup_failure(&mut self) -> Option<String> {
+        self.as_mut().and_then(Runtime::startup_failure)
+    }
 }
 
 #[doc(inline)]
@@ -95,29 +95,74 @@ impl EnvTemplate {
 /// Replace template variables in a string.
 ///
 /// Every time a template is found, the `replacement` function is called with the template key and value.
+/// `%{host}` is the only template allowed without a value and is passed an empty value.
+///
+/// The string is scanned once from left to right so templates in the replacements are left as is.
 pub fn replace_template(
     content: &mut String,
     mut replacement: impl FnMut(&str, &str) -> anyhow::Result<Option<String>>,
 ) -> Result<(), anyhow::Error> {
     let regex = TEMPLATE_REGEX.get_or_init(|| regex::Regex::new(r"%\{(.*?)\}").unwrap());
-    'outer: loop {
-        'inner: for captures in regex.captures_iter(content) {
-            let (Some(full), Some(capture)) = (captures.get(0), captures.get(1)) else {
-                continue 'inner;
-            };
-            let template = capture.as_str();
-            let (template_key, template_value) = template.split_once('=').with_context(|| {
-                format!("invalid template '{template}'(template should be in the form $KEY=$VALUE)")
-            })?;
-            let (template_key, template_value) = (template_key.trim(), template_value.trim());
-            if let Some(replacement) = replacement(template_key, template_value)? {
-                content.replace_range(full.range(), &replacement);
-                // Restart the search after a substitution
-                continue 'outer;
-            }
+    let mut replaced = String::with_capacity(content.len());
+    let mut last_end = 0;
+    for captures in regex.captures_iter(content) {
+        let (Some(full), Some(capture)) = (captures.get(0), captures.get(1)) else {
+            continue;
+        };
+        let template = capture.as_str();
+        let (template_key, template_value) = match template.split_once('=') {
+            Some(key_value) => key_value,
+            // `host` is the only template that takes no value
+            None if template.trim() == "host" => (template, ""),
+            None => anyhow::bail!(
+                "invalid template '{template}'(template should be in the form $KEY=$VALUE)"
+            ),
+        };
+        let (template_key, template_value) = (template_key.trim(), template_value.trim());
+        if let Some(replacement) = replacement(template_key, template_value)? {
+            replaced.push_str(&content[last_end..full.start()]);
+            replaced.push_str(&replacement);
+            last_end = full.end();
         }
-        // Break the outer loop if no substitutions were made
-        break 'outer;
     }
+    replaced.push_str(&content[last_end..]);
+    *content = replaced;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replacements_are_not_substituted_again() {
+        let mut content = String::from("a %{var=x} b %{var=y} c %{port=80}");
+        replace_template(&mut content, |key, value| {
+            Ok((key == "var").then(|| format!("%{{var={value}}}")))
+        })
+        .unwrap();
+        assert_eq!(content, "a %{var=x} b %{var=y} c %{port=80}");
+
+        let mut content = String::from("%{var=x}/%{var=y}");
+        replace_template(&mut content, |_, value| Ok(Some(value.repeat(2)))).unwrap();
+        assert_eq!(content, "xx/yy");
+    }
+
+    #[test]
+    fn only_host_may_omit_value() {
+        let mut content = String::from("http://%{host}:%{port=80}");
+        replace_template(&mut content, |key, value| {
+            Ok(Some(match key {
+                "host" => "127.0.0.1".to_owned(),
+                _ => value.to_owned(),
+            }))
+        })
+        .unwrap();
+        assert_eq!(content, "http://127.0.0.1:80");
+
+        for template in ["%{port}", "%{source}", "%{var}"] {
+            let error = replace_template(&mut template.to_owned(), |_, _| Ok(None)).unwrap_err();
+            assert!(error.to_string().contains("invalid template"), "{error}");
+        }
+    }
+}
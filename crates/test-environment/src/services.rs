@@ -3,15 +3,24 @@ use std::{
     path::{Path, PathBuf},
 };
 
+mod descriptor;
 mod docker;
+mod in_process;
 mod python;
+mod readiness;
 
 use anyhow::{bail, Context};
 
 use docker::DockerService;
+use in_process::InProcessService;
 use python::PythonService;
 
 pub use docker::DockerImage;
+pub use in_process::InProcessServer;
+pub use readiness::{Probe, Readiness};
+
+/// The host that services expose their ports on.
+pub const HOST: &str = "127.0.0.1";
 
 /// All the services that are running for a test.
 #[derive(Default)]
@@ -26,18 +35,40 @@ impl Services {
         std::fs::create_dir(&lock_dir).context("could not create service lock dir")?;
         let mut services = Vec::new();
         for service_def in config.service_definitions {
+            let readiness = service_def
+                .readiness
+                .unwrap_or_else(|| service_def.kind.default_readiness());
+            let name = &service_def.name;
+            let env = &service_def.env;
             let mut service: Box<dyn Service> = match service_def.kind {
                 ServiceKind::Python { script } => Box::new(PythonService::start(
-                    &service_def.name,
+                    name,
                     &script,
+                    env,
                     working_dir,
                     &lock_dir,
+                    readiness,
+                )?),
+                ServiceKind::Docker { image } => Box::new(DockerService::start(
+                    name, image, env, &lock_dir, readiness,
                 )?),
-                ServiceKind::Docker { image } => {
-                    Box::new(DockerService::start(&service_def.name, image, &lock_dir)?)
+                ServiceKind::InProcess { server } => {
+                    if !env.is_empty() {
+                        bail!("in-process service '{name}' does not support environment variables");
+                    }
+                    Box::new(InProcessService::start(
+                        name,
+                        server,
+                        working_dir,
+                        readiness,
+                    )?)
                 }
             };
             service.ready()?;
+            let ports = service.ports()?;
+            if let Some(port) = service_def.ports.iter().find(|p| !ports.contains_key(p)) {
+                bail!("service '{name}' does not expose port {port}");
+            }
             services.push(service);
         }
 
@@ -74,6 +105,31 @@ impl Services {
         }
         Ok(previous_result.map(|(_, p)| p))
     }
+
+    /// Get the host port that the service with the given name exposes a guest port on.
+    pub fn get_service_port(&mut self, name: &str, guest_port: u16) -> anyhow::Result<Option<u16>> {
+        let service = self
+            .get_mut(name)
+            .with_context(|| format!("no service named '{name}' is running"))?;
+        Ok(service.ports()?.get(&guest_port).copied())
+    }
+
+    /// Get the running service with the given name.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut (dyn Service + 'static)> {
+        self.services
+            .iter_mut()
+            .find(|s| s.name() == name)
+            .map(|s| s.as_mut())
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Services {
+    type Item = &'a mut Box<dyn Service>;
+    type IntoIter = std::slice::IterMut<'a, Box<dyn Service>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.services.iter_mut()
+    }
 }
 
 impl<'a> IntoIterator for &'a Services {
@@ -94,6 +150,8 @@ impl ServicesConfig {
     /// Create a new services config with a list of built-in services to start.
     ///
     /// The built-in services are expected to have a definition file in the `services` directory with the same name as the service.
+    /// This is either a `<name>.toml` service descriptor, a `<name>.py` script or a `<name>.Dockerfile`.
+    /// Services the built-in services depend on are started as well.
     pub fn new<'a>(builtins: impl Into<Vec<&'a str>>) -> anyhow::Result<Self> {
         let definitions_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("services");
         let service_definitions = get_builtin_service_definitions(
@@ -117,74 +175,127 @@ impl ServicesConfig {
     }
 }
 
-/// Get all of the service definitions returning a HashMap of the service name to the service definition file extension.
+/// Get the definitions of the built-in services and the services they depend on.
+///
+/// Services come after the services they depend on.
 fn get_builtin_service_definitions(
-    mut builtins: HashSet<&str>,
+    builtins: HashSet<&str>,
     service_definitions_path: &Path,
 ) -> anyhow::Result<Vec<ServiceDefinition>> {
-    if builtins.is_empty() {
-        return Ok(Vec::new());
-    }
-
-    let result = std::fs::read_dir(service_definitions_path)
-        .with_context(|| {
-            format!(
-                "no service definitions found at '{}'",
-                service_definitions_path.display()
-            )
-        })?
-        .map(|d| {
-            let d = d?;
-            if !d.file_type()?.is_file() {
-                bail!("directories are not allowed in the service definitions directory")
-            }
-            let file_name = d.file_name();
-            let file_name = file_name.to_str().unwrap();
-            let (file_name, file_extension) = file_name
-                .find('.')
-                .map(|i| (&file_name[..i], &file_name[i + 1..]))
-                .context("service definition did not have an extension")?;
-            Ok((file_name.to_owned(), file_extension.to_owned()))
-        })
-        .filter(|r| !matches!(r, Ok((_, extension)) if extension == "lock"))
-        .filter(|r| match r {
-            Ok((service, _)) => builtins.remove(service.as_str()),
-            _ => false,
-        })
-        .map(|r| {
-            let (name, extension) = r?;
-            Ok(ServiceDefinition {
-                name: name.clone(),
-                kind: match extension.as_str() {
-                    "py" => ServiceKind::Python {
-                        script: service_definitions_path.join(format!("{name}.py")),
-                    },
-                    "Dockerfile" => ServiceKind::Docker {
-                        image: docker::DockerImage::FromDockerfile(
-                            service_definitions_path.join(format!("{name}.Dockerfile")),
-                        ),
-                    },
-                    _ => bail!("unsupported service definition extension '{extension}'"),
-                },
-            })
-        })
-        .collect();
-    if !builtins.is_empty() {
-        bail!("no service definitions found for: {builtins:?}",);
+    let mut builtins = builtins.into_iter().collect::<Vec<_>>();
+    builtins.sort();
+    let mut definitions = Vec::new();
+    let mut missing = Vec::new();
+    for name in builtins {
+        add_builtin_service_definition(
+            name,
+            service_definitions_path,
+            &mut Vec::new(),
+            &mut definitions,
+            &mut missing,
+        )?;
+    }
+    if !missing.is_empty() {
+        bail!("no service definitions found for: {missing:?}",);
     }
-    result
+    Ok(definitions)
+}
+
+/// Add the definition of a built-in service after the definitions of its dependencies.
+///
+/// `dependents` are the services currently being added which depend on this service.
+fn add_builtin_service_definition(
+    name: &str,
+    service_definitions_path: &Path,
+    dependents: &mut Vec<String>,
+    definitions: &mut Vec<ServiceDefinition>,
+    missing: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    if definitions.iter().any(|d| d.name == name) || missing.iter().any(|m| m == name) {
+        return Ok(());
+    }
+    if dependents.iter().any(|d| d == name) {
+        bail!(
+            "services depend on each other: {} -> {name}",
+            dependents.join(" -> ")
+        );
+    }
+    let path = |extension: &str| service_definitions_path.join(format!("{name}.{extension}"));
+    let (definition, dependencies) = if path("toml").is_file() {
+        descriptor::load(name, &path("toml"))?
+    } else if path("py").is_file() {
+        let script = path("py");
+        let kind = ServiceKind::Python { script };
+        (ServiceDefinition::new(name, kind), Vec::new())
+    } else if path("Dockerfile").is_file() {
+        let image = DockerImage::FromDockerfile(path("Dockerfile"));
+        let kind = ServiceKind::Docker { image };
+        (ServiceDefinition::new(name, kind), Vec::new())
+    } else {
+        missing.push(name.to_owned());
+        return Ok(());
+    };
+    dependents.push(name.to_owned());
+    for dependency in &dependencies {
+        add_builtin_service_definition(
+            dependency,
+            service_definitions_path,
+            dependents,
+            definitions,
+            missing,
+        )?;
+    }
+    dependents.pop();
+    definitions.push(definition);
+    Ok(())
 }
 
 /// A service definition.
 pub struct ServiceDefinition {
     pub name: String,
     pub kind: ServiceKind,
+    /// How to tell that the service is ready, or `None` for the default of the kind of service.
+    pub readiness: Option<Readiness>,
+    /// The guest ports the service must expose once it's ready.
+    pub ports: Vec<u16>,
+    /// Environment variables set for the service.
+    pub env: HashMap<String, String>,
+}
+
+impl ServiceDefinition {
+    /// A definition of a service with the defaults for its kind.
+    pub fn new(name: impl Into<String>, kind: ServiceKind) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            readiness: None,
+            ports: Vec::new(),
+            env: HashMap::new(),
+        }
+    }
 }
 
 /// The kind of service.
 pub enum ServiceKind {
     Python { script: PathBuf },
     Docker { image: DockerImage },
+    InProcess { server: InProcessServer },
+}
+
+impl ServiceKind {
+    /// How services of this kind are probed unless configured otherwise.
+    ///
+    /// Python services print `READY` once they are ready and Docker services are ready once the
+    /// container is healthy.
+    pub fn default_readiness(&self) -> Readiness {
+        match self {
+            ServiceKind::Python { .. } => Readiness::new(Probe::LogLine("READY".into())),
+            ServiceKind::Docker { .. } => Readiness::new(Probe::ContainerHealth),
+            ServiceKind::InProcess { server } => {
+                Readiness::new(Probe::TcpConnect(server.guest_port()))
+            }
+        }
+    }
 }
 
 /// An external service a test may depend on.
@@ -197,4 +308,203 @@ pub trait Service {
 
     /// Get a mapping of ports that the service exposes.
     fn ports(&mut self) -> anyhow::Result<&HashMap<u16, u16>>;
+
+    /// Get everything the service has logged so far.
+    fn logs(&mut self) -> anyhow::Result<String>;
+
+    /// Check whether the service is ready according to the probe.
+    ///
+    /// Port based probes are supported by all services while services may support further probes.
+    fn probe(&mut self, probe: &Probe) -> anyhow::Result<bool> {
+        match self.ports() {
+            Ok(ports) => probe.check_ports(ports),
+            // The service may not know its ports before it's ready
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Run a command inside of the service (e.g. a CLI client for the service) and return its output.
+    fn exec(&mut self, args: &[&str]) -> anyhow::Result<std::process::Output> {
+        let _ = args;
+        bail!(
+            "service '{}' does not support running commands",
+            self.name()
+        )
+    }
+
+    /// Get the payloads the service has received so far.
+    ///
+    /// Only services which capture what they receive (e.g. the echo services) support this.
+    fn received(&mut self) -> anyhow::Result<Vec<Vec<u8>>> {
+        bail!(
+            "service '{}' does not capture what it receives",
+            self.name()
+        )
+    }
+
+    /// Start recording the HTTP requests the service receives.
+    ///
+    /// Only services which can record requests (e.g. the `http-echo` service) support this.
+    fn record_requests(&mut self) -> anyhow::Result<()> {
+        bail!("service '{}' does not record requests", self.name())
+    }
+
+    /// Get the HTTP requests recorded since [`Service::record_requests`] was called in the order
+    /// they were received.
+    fn requests(&mut self) -> anyhow::Result<Vec<RecordedRequest>> {
+        bail!("service '{}' does not record requests", self.name())
+    }
+}
+
+/// An HTTP request received by a service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// The path including the query.
+    pub path: String,
+    /// The headers in the order they were received.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write the service descriptors into a temporary services directory
+    fn services_dir(descriptors: &[(&str, &str)]) -> temp_dir::TempDir {
+        let dir = temp_dir::TempDir::new().unwrap();
+        for (name, descriptor) in descriptors {
+            std::fs::write(dir.path().join(format!("{name}.toml")), descriptor).unwrap();
+        }
+        dir
+    }
+
+    fn definitions(
+        builtins: &[&str],
+        descriptors: &[(&str, &str)],
+    ) -> anyhow::Result<Vec<ServiceDefinition>> {
+        let dir = services_dir(descriptors);
+        get_builtin_service_definitions(builtins.iter().copied().collect(), dir.path())
+    }
+
+    fn load_error(builtins: &[&str], descriptors: &[(&str, &str)]) -> String {
+        match definitions(builtins, descriptors) {
+            Ok(_) => panic!("expected loading the service definitions to fail"),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let definitions = definitions(
+            &["app"],
+            &[
+                (
+                    "app",
+                    "in-process = \"http-echo\"\ndepends-on = [\"db\", \"cache\"]",
+                ),
+                ("db", "in-process = \"tcp-echo\"\ndepends-on = [\"cache\"]"),
+                ("cache", "in-process = \"tcp-echo\""),
+            ],
+        )
+        .unwrap();
+        let names = definitions
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["cache", "db", "app"]);
+    }
+
+    #[test]
+    fn dependency_cycles_are_rejected() {
+        let error = load_error(
+            &["a"],
+            &[
+                ("a", "in-process = \"tcp-echo\"\ndepends-on = [\"b\"]"),
+                ("b", "in-process = \"tcp-echo\"\ndepends-on = [\"a\"]"),
+            ],
+        );
+        assert_eq!(error, "services depend on each other: a -> b -> a");
+    }
+
+    #[test]
+    fn missing_dependencies_are_rejected() {
+        let error = load_error(
+            &["a", "other"],
+            &[("a", "in-process = \"tcp-echo\"\ndepends-on = [\"missing\"]")],
+        );
+        assert_eq!(
+            error,
+            "no service definitions found for: [\"missing\", \"other\"]"
+        );
+    }
+
+    #[test]
+    fn exactly_one_implementation_is_required() {
+        for descriptor in [
+            "in-process = \"tcp-echo\"\nimage = \"redis:7\"",
+            "ports = [80]",
+        ] {
+            let error = load_error(&["a"], &[("a", descriptor)]);
+            assert!(
+                error.contains(
+                    "must have exactly one of `python`, `dockerfile`, `image` or `in-process`"
+                ),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let error = load_error(&["a"], &[("a", "in-process = \"tcp-echo\"\nport = 7")]);
+        assert!(error.contains("unknown field `port`"), "{error}");
+        let error = load_error(
+            &["a"],
+            &[(
+                "a",
+                "in-process = \"tcp-echo\"\nreadiness = { log = \"READY\" }",
+            )],
+        );
+        assert!(error.contains("unknown variant `log`"), "{error}");
+    }
+
+    #[test]
+    fn readiness_defaults_to_kind() {
+        let definitions = definitions(
+            &["default", "timeout", "probe"],
+            &[
+                ("default", "in-process = \"http-echo\""),
+                ("timeout", "in-process = \"http-echo\"\nstartup-timeout = 5"),
+                (
+                    "probe",
+                    "in-process = \"http-echo\"\nreadiness = { http-get = { port = 80, path = \"/\" } }",
+                ),
+            ],
+        )
+        .unwrap();
+        let readiness = |name: &str| {
+            definitions
+                .iter()
+                .find(|d| d.name == name)
+                .unwrap()
+                .readiness
+                .clone()
+        };
+        assert!(readiness("default").is_none());
+        let timeout = readiness("timeout").unwrap();
+        assert_eq!(timeout.probe(), &Probe::TcpConnect(80));
+        assert!(
+            format!("{timeout:?}").contains("timeout: 5s"),
+            "{timeout:?}"
+        );
+        assert_eq!(
+            readiness("probe").unwrap().probe(),
+            &Probe::HttpGet {
+                port: 80,
+                path: "/".into()
+            }
+        );
+    }
 }
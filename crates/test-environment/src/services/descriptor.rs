@@ -0,0 +1,123 @@
+use super::{DockerImage, InProcessServer, Probe, Readiness, ServiceDefinition, ServiceKind};
+use anyhow::{bail, Context as _};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// A declarative definition of a service read from a `<name>.toml` file
+///
+/// Exactly one of `python`, `dockerfile`, `image` and `in-process` names the implementation
+/// backing the service, e.g.:
+///
+/// ```toml
+/// dockerfile = "redis.Dockerfile"
+/// ports = [6379]
+/// readiness = { tcp-connect = 6379 }
+/// startup-timeout = 30
+/// backoff = { initial-ms = 100, max-ms = 2000 }
+/// ```
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ServiceDescriptor {
+    /// A python script relative to the descriptor
+    python: Option<PathBuf>,
+    /// A Dockerfile relative to the descriptor
+    dockerfile: Option<PathBuf>,
+    /// A docker image pulled from a registry
+    image: Option<String>,
+    /// A server run in the test process
+    in_process: Option<InProcessServer>,
+    /// The guest ports the service must expose
+    #[serde(default)]
+    ports: Vec<u16>,
+    /// Environment variables set for the service
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// How to tell the service is ready, defaulting to the probe for the kind of service
+    readiness: Option<Probe>,
+    /// How many seconds the service may take to become ready
+    startup_timeout: Option<u64>,
+    /// How long to wait between readiness probes
+    backoff: Option<Backoff>,
+    /// The services that must be started before this one
+    #[serde(default)]
+    depends_on: Vec<String>,
+}
+
+/// How long to wait between readiness probes, doubling after every probe up to the maximum
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Backoff {
+    initial_ms: u64,
+    max_ms: u64,
+}
+
+/// Load the definition of the service `name` from the descriptor at `path`
+///
+/// Returns the definition along with the names of the services it depends on.
+pub(super) fn load(name: &str, path: &Path) -> anyhow::Result<(ServiceDefinition, Vec<String>)> {
+    let descriptor = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read service descriptor '{}'", path.display()))?;
+    let descriptor: ServiceDescriptor = toml::from_str(&descriptor)
+        .with_context(|| format!("invalid service descriptor '{}'", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    let mut kinds = Vec::new();
+    if let Some(script) = descriptor.python {
+        kinds.push(ServiceKind::Python {
+            script: dir.join(script),
+        });
+    }
+    if let Some(dockerfile) = descriptor.dockerfile {
+        kinds.push(ServiceKind::Docker {
+            image: DockerImage::FromDockerfile(dir.join(dockerfile)),
+        });
+    }
+    if let Some(image) = descriptor.image {
+        kinds.push(ServiceKind::Docker {
+            image: DockerImage::FromRegistry(image),
+        });
+    }
+    if let Some(server) = descriptor.in_process {
+        kinds.push(ServiceKind::InProcess { server });
+    }
+    let kind = match <[ServiceKind; 1]>::try_from(kinds) {
+        Ok([kind]) => kind,
+        Err(_) => bail!(
+            "service descriptor '{}' must have exactly one of `python`, `dockerfile`, `image` or `in-process`",
+            path.display()
+        ),
+    };
+
+    let customized = descriptor.readiness.is_some()
+        || descriptor.startup_timeout.is_some()
+        || descriptor.backoff.is_some();
+    let readiness = customized.then(|| {
+        // Only the settings given in the descriptor replace those of the default readiness
+        let mut readiness = match descriptor.readiness {
+            Some(probe) => Readiness::new(probe),
+            None => kind.default_readiness(),
+        };
+        if let Some(secs) = descriptor.startup_timeout {
+            readiness = readiness.timeout(Duration::from_secs(secs));
+        }
+        if let Some(Backoff { initial_ms, max_ms }) = descriptor.backoff {
+            readiness = readiness.backoff(
+                Duration::from_millis(initial_ms),
+                Duration::from_millis(max_ms),
+            );
+        }
+        readiness
+    });
+
+    let definition = ServiceDefinition {
+        name: name.to_owned(),
+        kind,
+        readiness,
+        ports: descriptor.ports,
+        env: descriptor.env,
+    };
+    Ok((definition, descriptor.depends_on))
+}
@@ -1,4 +1,4 @@
-use super::Service;
+use super::{Probe, Readiness, Service};
 use anyhow::{bail, Context as _};
 use std::{
     cell::OnceCell,
@@ -14,6 +14,7 @@ pub struct DockerService {
     // We declare lock after container so that the lock is dropped after the container is
     _lock: fslock::LockFile,
     ports: OnceCell<HashMap<u16, u16>>,
+    readiness: Readiness,
     ready: bool,
 }
 
@@ -22,7 +23,9 @@ impl DockerService {
     pub fn start(
         name: impl Into<String>,
         image: DockerImage,
+        env: &HashMap<String, String>,
         lock_dir: &Path,
+        readiness: Readiness,
     ) -> anyhow::Result<Self> {
         let name = name.into();
         let lock_path = lock_dir.join(format!("{name}.lock"));
@@ -39,13 +42,14 @@ impl DockerService {
             }
             DockerImage::FromRegistry(image_name) => image_name,
         };
-        let container = run_container(image_name)?;
+        let container = run_container(image_name, env)?;
 
         Ok(Self {
             name,
             container,
             _lock: lock,
             ports: OnceCell::new(),
+            readiness,
             ready: false,
         })
     }
@@ -57,6 +61,66 @@ struct Container {
 }
 
 impl Container {
+    /// Check the health of the container
+    ///
+    /// Containers without a health check are considered healthy while unhealthy containers are an error.
+    fn healthy(&self, service_name: &str) -> anyhow::Result<bool> {
+        // docker container inspect -f '{{.State.Health.Status}}'
+        let output = Command::new("docker")
+            .arg("container")
+            .arg("inspect")
+            .arg("-f")
+            // Ensure that .State.Health exists and otherwise just print that it's healthy
+            .arg("{{with .State.Health}}{{.Status}}{{else}}healthy{{end}}")
+            .arg(&self.id)
+            .output()
+            .with_context(|| {
+                format!("failed to determine container health for '{service_name}' service")
+            })?;
+        if !output.status.success() {
+            let stderr = std::str::from_utf8(&output.stderr).unwrap_or("<non-utf8>");
+            bail!("docker health status check failed for service '{service_name}': {stderr}");
+        }
+        let output = String::from_utf8(output.stdout)?;
+        match output.trim() {
+            "healthy" => Ok(true),
+            "unhealthy" => {
+                let output = Command::new("docker")
+                    .arg("container")
+                    .arg("inspect")
+                    .arg("-f")
+                    // Ensure that .State.Health exists and otherwise just print that there are no logs
+                    .arg("{{with .State.Health}}{{json .Log}}{{else}}<NO LOG>{{end}}")
+                    .arg(&self.id)
+                    .output();
+                let logs = output
+                    .as_ref()
+                    .map(|o| String::from_utf8_lossy(&o.stdout))
+                    .unwrap_or_else(|_| "<failed to get health check logs>".into());
+                bail!("docker container for '{service_name}' service is unhealthy:\n{logs}")
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Get the last `lines` lines of the container's output
+    fn logs(&self, lines: usize) -> String {
+        let mut command = Command::new("docker");
+        command.arg("logs");
+        if lines != usize::MAX {
+            command.arg("--tail").arg(lines.to_string());
+        }
+        match command.arg(&self.id).output() {
+            // Containers write to both stdout and stderr
+            Ok(output) => format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => format!("<failed to get container logs: {e}>"),
+        }
+    }
+
     fn get_ports(&self) -> anyhow::Result<HashMap<u16, u16>> {
         let output = Command::new("docker")
             .arg("port")
@@ -120,60 +184,43 @@ impl Drop for Container {
 
 impl Service for DockerService {
     fn name(&self) -> &str {
-        "docker"
+        &self.name
     }
 
     fn ready(&mut self) -> anyhow::Result<()> {
-        // docker container inspect -f '{{.State.Health.Status}}'
-        while !self.ready {
-            let output = Command::new("docker")
-                .arg("container")
-                .arg("inspect")
-                .arg("-f")
-                // Ensure that .State.Health exists and otherwise just print that it's healthy
-                .arg("{{with .State.Health}}{{.Status}}{{else}}healthy{{end}}")
-                .arg(&self.container.id)
-                .output()
-                .with_context(|| {
-                    format!(
-                        "failed to determine container health for '{}' service",
-                        self.name
-                    )
-                })?;
-            if !output.status.success() {
-                let stderr = std::str::from_utf8(&output.stderr).unwrap_or("<non-utf8>");
-                bail!(
-                    "docker health status check failed for service '{}': {stderr}",
-                    self.name
+        if !self.ready {
+            let readiness = self.readiness.clone();
+            if let Err(e) = readiness.wait(|probe| self.probe(probe)) {
+                anyhow::bail!(
+                    "service '{}' is not ready: {e:#}\nrecent output:\n{}",
+                    self.name,
+                    self.container.logs(20)
                 );
             }
-            let output = String::from_utf8(output.stdout)?;
-            match output.trim() {
-                "healthy" => self.ready = true,
-                "unhealthy" => {
-                    let output = Command::new("docker")
-                        .arg("container")
-                        .arg("inspect")
-                        .arg("-f")
-                        // Ensure that .State.Health exists and otherwise just print that there are no logs
-                        .arg("{{with .State.Health}}{{json .Log}}{{else}}<NO LOG>{{end}}")
-                        .arg(&self.container.id)
-                        .output();
-                    let logs = output
-                        .as_ref()
-                        .map(|o| String::from_utf8_lossy(&o.stdout))
-                        .unwrap_or_else(|_| "<failed to get health check logs>".into());
-                    bail!(
-                        "docker container for '{}' service is unhealthy:\n{logs}",
-                        self.name
-                    )
-                }
-                _ => std::thread::sleep(std::time::Duration::from_millis(100)),
-            }
+            self.ready = true;
         }
         Ok(())
     }
 
+    fn probe(&mut self, probe: &Probe) -> anyhow::Result<bool> {
+        match probe {
+            Probe::ContainerHealth => self.container.healthy(&self.name),
+            Probe::LogLine(line) => Ok(self
+                .container
+                .logs(usize::MAX)
+                .lines()
+                .any(|l| l.contains(line.as_str()))),
+            probe => match self.ports() {
+                Ok(ports) => probe.check_ports(ports),
+                Err(_) => Ok(false),
+            },
+        }
+    }
+
+    fn logs(&mut self) -> anyhow::Result<String> {
+        Ok(self.container.logs(usize::MAX))
+    }
+
     fn ports(&mut self) -> anyhow::Result<&HashMap<u16, u16>> {
         match self.ports.get() {
             Some(p) => Ok(p),
@@ -183,6 +230,15 @@ impl Service for DockerService {
             }
         }
     }
+
+    fn exec(&mut self, args: &[&str]) -> anyhow::Result<std::process::Output> {
+        Command::new("docker")
+            .arg("exec")
+            .arg(&self.container.id)
+            .args(args)
+            .output()
+            .with_context(|| format!("failed to run command in '{}' service", self.name))
+    }
 }
 
 fn build_image(dockerfile_path: &Path, image_name: &String) -> anyhow::Result<()> {
@@ -216,12 +272,16 @@ fn build_image(dockerfile_path: &Path, image_name: &String) -> anyhow::Result<()
     Ok(())
 }
 
-fn run_container(image_name: String) -> anyhow::Result<Container> {
+fn run_container(image_name: String, env: &HashMap<String, String>) -> anyhow::Result<Container> {
     let output = Command::new("docker")
         .arg("run")
         .arg("-d")
         .arg("-P")
         .arg("--health-start-period=1s")
+        .args(
+            env.iter()
+                .flat_map(|(k, v)| ["-e".to_owned(), format!("{k}={v}")]),
+        )
         .arg(&image_name)
         .output()
         .with_context(|| format!("docker run failed to spawn for image '{image_name}'"))?;
@@ -0,0 +1,484 @@
+use super::{Readiness, RecordedRequest, Service};
+use anyhow::{bail, Context as _};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read as _, Write as _},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// A server implemented in Rust that runs inside of the test process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InProcessServer {
+    /// An HTTP server on guest port 80 which responds with the body of each request
+    HttpEcho,
+    /// A TCP server on guest port 7 which sends back everything it receives
+    TcpEcho,
+    /// An HTTP server on guest port 80 which responds to `GET` requests with canned bodies
+    ///
+    /// The responses are read from `responses.txt` in the working directory which has a
+    /// `<path> <body>` line for each path.
+    HttpResponses,
+}
+
+impl InProcessServer {
+    pub(super) fn guest_port(self) -> u16 {
+        match self {
+            InProcessServer::HttpEcho | InProcessServer::HttpResponses => 80,
+            InProcessServer::TcpEcho => 7,
+        }
+    }
+}
+
+/// A server running on a background thread as a service
+pub struct InProcessService {
+    name: String,
+    server: InProcessServer,
+    ports: HashMap<u16, u16>,
+    recorded: Arc<Mutex<Recorded>>,
+    stop: Arc<AtomicBool>,
+    readiness: Readiness,
+    ready: bool,
+}
+
+/// What the server has received so far
+#[derive(Default)]
+struct Recorded {
+    received: Vec<Vec<u8>>,
+    /// The requests received since recording was switched on, if it is
+    requests: Option<Vec<RecordedRequest>>,
+    log: String,
+}
+
+impl Recorded {
+    fn log(&mut self, line: impl std::fmt::Display) {
+        self.log.push_str(&format!("{line}\n"));
+    }
+}
+
+impl InProcessService {
+    pub fn start(
+        name: &str,
+        server: InProcessServer,
+        working_dir: &Path,
+        readiness: Readiness,
+    ) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind((super::HOST, 0))
+            .with_context(|| format!("failed to bind listener for service '{name}'"))?;
+        let port = listener.local_addr()?.port();
+        let recorded = Arc::<Mutex<Recorded>>::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler = Handler {
+            server,
+            working_dir: working_dir.to_owned(),
+            recorded: recorded.clone(),
+        };
+        let stopped = stop.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = handler.handle(stream) {
+                        let mut recorded = handler.recorded.lock().unwrap();
+                        recorded.log(format_args!("error handling connection: {e:#}"));
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            name: name.to_owned(),
+            server,
+            ports: HashMap::from([(server.guest_port(), port)]),
+            recorded,
+            stop,
+            readiness,
+            ready: false,
+        })
+    }
+}
+
+impl Service for InProcessService {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn ready(&mut self) -> anyhow::Result<()> {
+        if !self.ready {
+            let readiness = self.readiness.clone();
+            readiness
+                .wait(|probe| self.probe(probe))
+                .with_context(|| format!("service '{}' is not ready", self.name))?;
+            self.ready = true;
+        }
+        Ok(())
+    }
+
+    fn ports(&mut self) -> anyhow::Result<&HashMap<u16, u16>> {
+        Ok(&self.ports)
+    }
+
+    fn logs(&mut self) -> anyhow::Result<String> {
+        Ok(self.recorded.lock().unwrap().log.clone())
+    }
+
+    fn received(&mut self) -> anyhow::Result<Vec<Vec<u8>>> {
+        Ok(self.recorded.lock().unwrap().received.clone())
+    }
+
+    fn record_requests(&mut self) -> anyhow::Result<()> {
+        if self.server != InProcessServer::HttpEcho {
+            bail!("service '{}' does not record requests", self.name);
+        }
+        self.recorded
+            .lock()
+            .unwrap()
+            .requests
+            .get_or_insert_with(Vec::new);
+        Ok(())
+    }
+
+    fn requests(&mut self) -> anyhow::Result<Vec<RecordedRequest>> {
+        self.recorded
+            .lock()
+            .unwrap()
+            .requests
+            .clone()
+            .with_context(|| format!("service '{}' is not recording requests", self.name))
+    }
+}
+
+impl Drop for InProcessService {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up the listener so that it notices it should stop
+        for port in self.ports.values() {
+            let _ = TcpStream::connect((super::HOST, *port));
+        }
+    }
+}
+
+/// Handles the connections made to a server
+#[derive(Clone)]
+struct Handler {
+    server: InProcessServer,
+    working_dir: PathBuf,
+    recorded: Arc<Mutex<Recorded>>,
+}
+
+impl Handler {
+    fn handle(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        if self.server == InProcessServer::TcpEcho {
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            loop {
+                let n = stream.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                received.extend(&buffer[..n]);
+                stream.write_all(&buffer[..n])?;
+            }
+            // Record everything received on the connection so that tests can check it
+            let mut recorded = self.recorded.lock().unwrap();
+            recorded.log(format_args!("received {} bytes", received.len()));
+            recorded.received.push(received);
+            return Ok(());
+        }
+
+        let Some(request) = read_request(&mut BufReader::new(&stream))? else {
+            // Connections may be closed without sending a request (e.g. by readiness probes)
+            return Ok(());
+        };
+        let (status, body) = match self.server {
+            InProcessServer::HttpEcho => {
+                let body = request.body.clone();
+                let mut recorded = self.recorded.lock().unwrap();
+                recorded.received.push(request.body.clone());
+                if let Some(requests) = &mut recorded.requests {
+                    requests.push(request.clone());
+                }
+                ((200, "OK"), body)
+            }
+            InProcessServer::HttpResponses => self.canned_response(&request)?,
+            InProcessServer::TcpEcho => unreachable!(),
+        };
+        self.recorded.lock().unwrap().log(format_args!(
+            "\"{} {}\" {}",
+            request.method, request.path, status.0
+        ));
+        write_response(&mut stream, &request.method, status, &body)
+    }
+
+    fn canned_response(
+        &self,
+        request: &RecordedRequest,
+    ) -> anyhow::Result<((u16, &'static str), Vec<u8>)> {
+        if request.method != "GET" {
+            return Ok(((501, "Not Implemented"), b"Unsupported method".to_vec()));
+        }
+        let path = request.path.split(['?', '#']).next().unwrap_or_default();
+        let responses = std::fs::read_to_string(self.working_dir.join("responses.txt"))
+            .context("failed to read responses.txt")?;
+        let body = responses.lines().find_map(|line| {
+            let (response_path, body) = line.trim().split_once(' ')?;
+            (response_path == path).then_some(body)
+        });
+        Ok(match body {
+            Some(body) => ((200, "OK"), body.as_bytes().to_vec()),
+            None => ((404, "Not Found"), b"Not Found".to_vec()),
+        })
+    }
+}
+
+/// Read an HTTP/1.1 request from the stream
+///
+/// Returns `None` if the stream ends before a request is sent.
+fn read_request(reader: &mut impl BufRead) -> anyhow::Result<Option<RecordedRequest>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        bail!("malformed request line '{}'", line.trim_end());
+    };
+    let (method, path) = (method.to_owned(), path.to_owned());
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header
+            .split_once(':')
+            .with_context(|| format!("malformed header '{header}'"))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+    let body = if header("transfer-encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked")) {
+        read_chunked_body(reader)?
+    } else {
+        let length = header("content-length")
+            .map(str::parse)
+            .transpose()
+            .context("malformed content-length header")?
+            .unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        body
+    };
+
+    Ok(Some(RecordedRequest {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+/// Read a body sent with chunked transfer encoding
+fn read_chunked_body(reader: &mut impl BufRead) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        // The size may be followed by chunk extensions which are ignored
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .with_context(|| format!("malformed chunk size '{}'", line.trim_end()))?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        // Each chunk is followed by a CRLF
+        line.clear();
+        reader.read_line(&mut line)?;
+    }
+    // Skip any trailers up to the final empty line
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok(body)
+}
+
+/// Write a plain text response and close the connection
+fn write_response(
+    stream: &mut TcpStream,
+    method: &str,
+    (status, reason): (u16, &str),
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    if method != "HEAD" {
+        response.extend(body);
+    }
+    stream
+        .write_all(&response)
+        .context("failed to write response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::Probe;
+    use std::time::{Duration, Instant};
+
+    fn start(server: InProcessServer, working_dir: &Path) -> InProcessService {
+        let readiness = Readiness::new(Probe::TcpConnect(server.guest_port()));
+        let mut service = InProcessService::start("test", server, working_dir, readiness).unwrap();
+        service.ready().unwrap();
+        service
+    }
+
+    fn host_port(service: &mut InProcessService) -> u16 {
+        *service.ports().unwrap().values().next().unwrap()
+    }
+
+    /// Send a raw request and return the raw response once the server closes the connection
+    fn send(service: &mut InProcessService, request: &str) -> String {
+        let mut stream = TcpStream::connect((crate::services::HOST, host_port(service))).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn http_echo_records_requests_once_asked_to() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let mut service = start(InProcessServer::HttpEcho, dir.path());
+        send(
+            &mut service,
+            "POST /before HTTP/1.1\r\nContent-Length: 5\r\n\r\nfirst",
+        );
+        assert!(service.requests().is_err());
+
+        service.record_requests().unwrap();
+        let response = send(
+            &mut service,
+            "PUT /after?x=1 HTTP/1.1\r\nX-Test: a\r\nx-test: b\r\nContent-Length: 6\r\n\r\nsecond",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nsecond"), "{response}");
+
+        let requests = service.requests().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(requests[0].path, "/after?x=1");
+        assert_eq!(
+            requests[0].headers,
+            [
+                ("X-Test".to_owned(), "a".to_owned()),
+                ("x-test".to_owned(), "b".to_owned()),
+                ("Content-Length".to_owned(), "6".to_owned())
+            ]
+        );
+        assert_eq!(requests[0].body, b"second");
+        // Bodies are received whether or not requests are recorded
+        assert_eq!(
+            service.received().unwrap(),
+            [b"first".to_vec(), b"second".to_vec()]
+        );
+    }
+
+    #[test]
+    fn only_http_echo_records_requests() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let mut service = start(InProcessServer::TcpEcho, dir.path());
+        assert!(service.record_requests().is_err());
+    }
+
+    #[test]
+    fn http_echo_reads_chunked_bodies() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let mut service = start(InProcessServer::HttpEcho, dir.path());
+        let response = send(
+            &mut service,
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;ext=1\r\nhel\r\na\r\nlo, world!\r\n0\r\nTrailer: x\r\n\r\n",
+        );
+        assert!(response.contains("Content-Length: 13\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nhello, world!"), "{response}");
+        assert_eq!(service.received().unwrap(), [b"hello, world!".to_vec()]);
+    }
+
+    #[test]
+    fn head_responses_have_no_body() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let mut service = start(InProcessServer::HttpEcho, dir.path());
+        let response = send(&mut service, "HEAD / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+    }
+
+    #[test]
+    fn http_responses_are_read_from_file() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("responses.txt"),
+            "/hello Hello, world!\n  /other other  \n",
+        )
+        .unwrap();
+        let mut service = start(InProcessServer::HttpResponses, dir.path());
+        let get = |service: &mut InProcessService, path: &str| {
+            send(service, &format!("GET {path} HTTP/1.1\r\n\r\n"))
+        };
+
+        let response = get(&mut service, "/hello?query#fragment");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nHello, world!"), "{response}");
+        assert!(get(&mut service, "/other").ends_with("\r\n\r\nother"));
+        assert!(get(&mut service, "/missing").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = send(&mut service, "POST /hello HTTP/1.1\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 501 Not Implemented\r\n"),
+            "{response}"
+        );
+        assert!(service.logs().unwrap().contains("\"GET /missing\" 404"));
+    }
+
+    #[test]
+    fn dropping_the_service_closes_the_listener() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let mut service = start(InProcessServer::TcpEcho, dir.path());
+        let port = host_port(&mut service);
+        drop(service);
+        let start = Instant::now();
+        while TcpStream::connect((crate::services::HOST, port)).is_ok() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "listener still accepts connections"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
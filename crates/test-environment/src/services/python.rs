@@ -1,6 +1,6 @@
 use crate::io::OutputStream;
 
-use super::Service;
+use super::{readiness::tail, Probe, Readiness, Service};
 use anyhow::Context as _;
 use std::{
     cell::OnceCell,
@@ -11,10 +11,13 @@ use std::{
 
 /// A python script as a service
 pub struct PythonService {
+    name: String,
     child: std::process::Child,
     stdout: OutputStream,
+    stderr: OutputStream,
     ports: OnceCell<HashMap<u16, u16>>,
     _lock: fslock::LockFile,
+    readiness: Readiness,
     ready: bool,
 }
 
@@ -22,8 +25,10 @@ impl PythonService {
     pub fn start(
         name: &str,
         script_path: &Path,
+        env: &HashMap<String, String>,
         working_dir: &Path,
         lock_dir: &Path,
+        readiness: Readiness,
     ) -> anyhow::Result<Self> {
         let lock_path = lock_dir.join(format!("{name}.lock"));
         let mut lock =
@@ -32,20 +37,29 @@ impl PythonService {
         let mut child = python()
             .current_dir(working_dir)
             .arg(script_path.display().to_string())
+            .envs(env)
             .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
             .with_context(|| format!("python failed to spawn for '{}'", script_path.display()))?;
-        std::thread::sleep(std::time::Duration::from_millis(1000));
         Ok(Self {
+            name: name.to_owned(),
             stdout: OutputStream::new(
                 child
                     .stdout
                     .take()
                     .expect("child process somehow does not have stdout"),
             ),
+            stderr: OutputStream::new(
+                child
+                    .stderr
+                    .take()
+                    .expect("child process somehow does not have stderr"),
+            ),
             child,
             ports: OnceCell::new(),
             _lock: lock,
+            readiness,
             ready: false,
         })
     }
@@ -53,18 +67,21 @@ impl PythonService {
 
 impl Service for PythonService {
     fn name(&self) -> &str {
-        "python"
+        &self.name
     }
 
     fn ready(&mut self) -> anyhow::Result<()> {
-        while !self.ready {
-            let stdout = self
-                .stdout
-                .output_as_str()
-                .context("stdout is not valid utf8")?;
-            if stdout.contains("READY") {
-                self.ready = true;
+        if !self.ready {
+            let readiness = self.readiness.clone();
+            if let Err(e) = readiness.wait(|probe| self.probe(probe)) {
+                let output = self.logs()?;
+                anyhow::bail!(
+                    "service '{}' is not ready: {e:#}\nrecent output:\n{}",
+                    self.name,
+                    tail(&output, 20)
+                );
             }
+            self.ready = true;
         }
         let exit = self.child.try_wait()?;
         if exit.is_some() {
@@ -78,6 +95,34 @@ impl Service for PythonService {
         Ok(())
     }
 
+    fn probe(&mut self, probe: &Probe) -> anyhow::Result<bool> {
+        if let Some(status) = self.child.try_wait()? {
+            anyhow::bail!("python service process exited early with {status}");
+        }
+        match probe {
+            Probe::LogLine(line) => {
+                let stdout = self
+                    .stdout
+                    .output_as_str()
+                    .context("stdout is not valid utf8")?;
+                Ok(stdout.lines().any(|l| l.contains(line.as_str())))
+            }
+            probe => match self.ports() {
+                Ok(ports) => probe.check_ports(ports),
+                Err(_) => Ok(false),
+            },
+        }
+    }
+
+    fn logs(&mut self) -> anyhow::Result<String> {
+        let mut logs = String::from_utf8_lossy(self.stdout.output()).into_owned();
+        let stderr = String::from_utf8_lossy(self.stderr.output());
+        if !stderr.is_empty() {
+            logs.push_str(&format!("stderr:\n{stderr}"));
+        }
+        Ok(logs)
+    }
+
     fn ports(&mut self) -> anyhow::Result<&HashMap<u16, u16>> {
         let stdout = self
             .stdout
@@ -104,6 +149,12 @@ impl Service for PythonService {
                     })
                     .filter_map(|r| r.transpose())
                     .collect::<anyhow::Result<HashMap<_, _>>>()?;
+                // Ports are printed before the service is ready so they may not be known yet
+                anyhow::ensure!(
+                    self.ready || !ports.is_empty(),
+                    "service '{}' has not printed its ports yet",
+                    self.name
+                );
                 Ok(self.ports.get_or_init(|| ports))
             }
         }
@@ -0,0 +1,207 @@
+use anyhow::bail;
+use std::{
+    collections::HashMap,
+    io::{Read as _, Write as _},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+/// How to tell that a service is ready to be used.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Probe {
+    /// The service has printed a line containing this text.
+    LogLine(String),
+    /// The host port the service exposes this guest port on accepts TCP connections.
+    TcpConnect(u16),
+    /// A `GET` request for the path to the host port the service exposes the guest port on gets a
+    /// successful response.
+    HttpGet { port: u16, path: String },
+    /// The container the service runs in reports itself as healthy.
+    ContainerHealth,
+}
+
+impl Probe {
+    /// Check a probe that only needs the ports the service exposes.
+    ///
+    /// Returns an error for probes that need the cooperation of the service.
+    pub fn check_ports(&self, ports: &HashMap<u16, u16>) -> anyhow::Result<bool> {
+        let (guest_port, path) = match self {
+            Probe::TcpConnect(port) => (port, None),
+            Probe::HttpGet { port, path } => (port, Some(path)),
+            Probe::LogLine(_) | Probe::ContainerHealth => {
+                bail!("probe {self:?} is not supported by the service")
+            }
+        };
+        let Some(host_port) = ports.get(guest_port) else {
+            return Ok(false);
+        };
+        let addr = std::net::SocketAddr::new(super::HOST.parse()?, *host_port);
+        let Ok(mut stream) = TcpStream::connect_timeout(&addr, Duration::from_secs(1)) else {
+            return Ok(false);
+        };
+        let Some(path) = path else {
+            return Ok(true);
+        };
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {}:{host_port}\r\nConnection: close\r\n\r\n",
+            super::HOST
+        );
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut response = Vec::new();
+        if stream.write_all(request.as_bytes()).is_err()
+            || stream.read_to_end(&mut response).is_err()
+        {
+            return Ok(false);
+        }
+        // The status line looks like `HTTP/1.1 200 OK`
+        let status = String::from_utf8_lossy(&response)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok());
+        Ok(status.is_some_and(|s| (200..300).contains(&s)))
+    }
+}
+
+/// How a service is probed until it is ready.
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    probe: Probe,
+    timeout: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Readiness {
+    /// Probe with the default timeout of 60 seconds and backoff starting at 50ms.
+    pub fn new(probe: Probe) -> Self {
+        Self {
+            probe,
+            timeout: Duration::from_secs(60),
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// How long to keep probing before giving up.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long to wait between probes which doubles after every probe up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// The probe used to check whether the service is ready.
+    pub fn probe(&self) -> &Probe {
+        &self.probe
+    }
+
+    /// Run the probe until it succeeds, fails or the timeout passes.
+    pub fn wait(
+        &self,
+        mut probe: impl FnMut(&Probe) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let mut backoff = self.initial_backoff;
+        loop {
+            if probe(&self.probe)? {
+                return Ok(());
+            }
+            let Some(remaining) = self.timeout.checked_sub(start.elapsed()) else {
+                bail!(
+                    "probe {:?} did not succeed within {:?}",
+                    self.probe,
+                    self.timeout
+                );
+            };
+            std::thread::sleep(backoff.min(remaining));
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+}
+
+/// The last `lines` lines of the output.
+pub(crate) fn tail(output: &str, lines: usize) -> &str {
+    let start = output
+        .trim_end()
+        .rmatch_indices('\n')
+        .nth(lines.saturating_sub(1))
+        .map(|(i, _)| i + 1)
+        .unwrap_or(0);
+    &output[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tail_keeps_last_lines() {
+        assert_eq!(tail("a\nb\nc\n", 2), "b\nc\n");
+        assert_eq!(tail("a\nb\nc", 2), "b\nc");
+        assert_eq!(tail("a\nb", 5), "a\nb");
+        assert_eq!(tail("", 3), "");
+    }
+
+    #[test]
+    fn wait_retries_until_probe_succeeds() {
+        let readiness = Readiness::new(Probe::TcpConnect(80))
+            .backoff(Duration::from_millis(1), Duration::from_millis(4));
+        let mut probes = 0;
+        readiness
+            .wait(|_| {
+                probes += 1;
+                Ok(probes == 5)
+            })
+            .unwrap();
+        assert_eq!(probes, 5);
+    }
+
+    #[test]
+    fn wait_backs_off_up_to_max() {
+        let readiness = Readiness::new(Probe::TcpConnect(80))
+            .timeout(Duration::from_millis(200))
+            .backoff(Duration::from_millis(10), Duration::from_millis(40));
+        let mut times = Vec::new();
+        let _ = readiness.wait(|_| {
+            times.push(Instant::now());
+            Ok(false)
+        });
+        let gaps = times.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        assert!(gaps[0] >= Duration::from_millis(10), "{gaps:?}");
+        assert!(gaps[1] >= Duration::from_millis(20), "{gaps:?}");
+        // The backoff doesn't grow past the maximum (with slack for a busy machine)
+        assert!(
+            gaps.iter().all(|gap| *gap < Duration::from_millis(40) * 3),
+            "{gaps:?}"
+        );
+    }
+
+    #[test]
+    fn wait_times_out() {
+        let readiness = Readiness::new(Probe::LogLine("READY".into()))
+            .timeout(Duration::from_millis(50))
+            .backoff(Duration::from_millis(5), Duration::from_millis(10));
+        let start = Instant::now();
+        let error = readiness.wait(|_| Ok(false)).unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            error.to_string(),
+            "probe LogLine(\"READY\") did not succeed within 50ms"
+        );
+    }
+
+    #[test]
+    fn wait_stops_on_probe_error() {
+        let readiness = Readiness::new(Probe::ContainerHealth);
+        let error = readiness
+            .wait(|_| anyhow::bail!("container exited"))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "container exited");
+    }
+}
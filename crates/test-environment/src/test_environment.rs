@@ -127,6 +127,11 @@ impl<R> TestEnvironment<R> {
         self.services.get_port(guest_port)
     }
 
+    /// Get the host port that the named service maps to the given guest port
+    pub fn get_service_port(&mut self, name: &str, guest_port: u16) -> anyhow::Result<Option<u16>> {
+        self.services.get_service_port(name, guest_port)
+    }
+
     /// Write a file into the test environment at the given relative path
     pub fn write_file(
         &self,
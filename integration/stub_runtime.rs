@@ -0,0 +1,117 @@
+//! Runs the `run` command against the stub runtime to check it end to end
+
+use std::{path::Path, process::Command};
+
+#[test]
+fn run_passes_against_stub_runtime() {
+    let stub_runtime = build_stub_runtime();
+    let tests_dir = tempfile::tempdir().unwrap();
+    write_fixture(&tests_dir.path().join("request-shape"));
+    write_command_fixture(&tests_dir.path().join("command"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_conformance-tests-cli"))
+        .arg("run")
+        .arg(tests_dir.path())
+        .arg("--")
+        .arg(&stub_runtime)
+        .args(["--listen", "{addr}", "{manifest}", "{env}"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "run failed\nstdout:\n{stdout}\nstderr:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("request-shape"), "{stdout}");
+    assert!(stdout.contains("command"), "{stdout}");
+    assert!(stdout.contains("2 passed"), "{stdout}");
+}
+
+/// Build the stub runtime and return the path to its binary
+fn build_stub_runtime() -> std::path::PathBuf {
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--quiet", "-p", "stub-runtime"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "failed to build stub runtime");
+    // The stub runtime is built into the same target directory as the CLI
+    let cli = Path::new(env!("CARGO_BIN_EXE_conformance-tests-cli"));
+    cli.with_file_name(format!("stub-runtime{}", std::env::consts::EXE_SUFFIX))
+}
+
+/// Write a test the stub runtime passes, as it always responds with an empty `200 OK`
+fn write_fixture(dir: &Path) {
+    std::fs::create_dir(dir).unwrap();
+    std::fs::write(
+        dir.join("spin.toml"),
+        r#"spin_manifest_version = 2
+
+[application]
+name = "request-shape"
+
+[[trigger.http]]
+route = "/..."
+component = "request-shape"
+
+[component.request-shape]
+source = "request-shape.wasm"
+"#,
+    )
+    .unwrap();
+    std::fs::write(dir.join("request-shape.wasm"), b"").unwrap();
+    std::fs::write(
+        dir.join("test.json5"),
+        r#"{
+    "invocations": [
+        {
+            "request": { "path": "/" },
+            "response": {
+                "headers": [
+                    { "name": "Content-Length", "value": "0" },
+                    { "name": "Date", "optional": true },
+                ],
+            },
+        },
+    ],
+}"#,
+    )
+    .unwrap();
+}
+
+/// Write a command app test which only passes if its environment is passed on with `{env}`
+fn write_command_fixture(dir: &Path) {
+    std::fs::create_dir(dir).unwrap();
+    std::fs::write(
+        dir.join("spin.toml"),
+        r#"spin_manifest_version = 2
+
+[application]
+name = "command"
+
+[[trigger.command]]
+component = "command"
+
+[component.command]
+source = "command.wasm"
+"#,
+    )
+    .unwrap();
+    std::fs::write(dir.join("command.wasm"), b"").unwrap();
+    std::fs::write(
+        dir.join("test.json5"),
+        r#"{
+    "invocations": [
+        {
+            "command": { "args": ["hello"], "env": { "GREETING": "hi" }, "stdin": "input" },
+            "output": { "stdout": "args: hello\nstdin: input\n", "stderr": "GREETING=hi\n" },
+        },
+        {
+            "command": { "env": { "EXIT_WITH_ERROR": "1" } },
+            "output": { "exit-code": 1 },
+        },
+    ],
+}"#,
+    )
+    .unwrap();
+}
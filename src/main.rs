@@ -1,10 +1,22 @@
+mod run;
+
 fn main() {
     let Some(command) = std::env::args().nth(1) else {
         eprintln!("Usage: test-runner <command>");
         std::process::exit(1);
     };
     let result = match command.as_str() {
-        "archive" => archive(),
+        "archive" => {
+            let sign_key = match (std::env::args().nth(2).as_deref(), std::env::args().nth(3)) {
+                (None, _) => None,
+                (Some("--sign-key"), Some(path)) => Some(PathBuf::from(path)),
+                _ => {
+                    eprintln!("Usage: test-runner archive [--sign-key <path>]");
+                    std::process::exit(1);
+                }
+            };
+            archive(sign_key.as_deref())
+        }
         "package" => {
             let dir = std::env::args()
                 .nth(2)
@@ -16,6 +28,21 @@ fn main() {
                     Ok(())
                 })
         }
+        "run" => {
+            let Some(tests_dir) = std::env::args().nth(2) else {
+                eprintln!(
+                    "Usage: test-runner run <tests-dir> [<test-args>... --] <runtime-command>..."
+                );
+                std::process::exit(1);
+            };
+            let args = std::env::args().skip(3).collect::<Vec<_>>();
+            // Arguments before a `--` are passed on to the test harness
+            let (test_args, runtime_command) = match args.iter().position(|a| a == "--") {
+                Some(i) => (args[..i].to_vec(), args[i + 1..].to_vec()),
+                None => (Vec::new(), args),
+            };
+            run::run(&tests_dir, test_args, runtime_command)
+        }
         _ => {
             eprintln!("Unknown command: {}", command);
             std::process::exit(1);
@@ -51,7 +78,11 @@ use std::sync::OnceLock;
 use tar::Builder;
 use tempfile::tempdir;
 
-fn archive() -> anyhow::Result<()> {
+/// Package the tests into a `tests.tar.gz`
+///
+/// The archive contains an index of all test files and their digests, which is signed with the
+/// hex encoded ed25519 secret key in the `sign_key` file if one is provided.
+fn archive(sign_key: Option<&Path>) -> anyhow::Result<()> {
     // Ensure the program exits if any command fails
     let output_tar = "tests.tar.gz";
 
@@ -63,6 +94,31 @@ fn archive() -> anyhow::Result<()> {
 
     package_into(temp_dir_path)?;
 
+    let index = conformance_tests::index::Index::generate(temp_dir_path)
+        .context("failed to index tests")?
+        .to_bytes()?;
+    std::fs::write(
+        temp_dir_path.join(conformance_tests::index::INDEX_FILE_NAME),
+        &index,
+    )
+    .context("failed to write test index")?;
+    if let Some(sign_key) = sign_key {
+        let secret_key = std::fs::read_to_string(sign_key)
+            .with_context(|| format!("failed to read signing key {sign_key:?}"))?;
+        let secret_key = conformance_tests::index::parse_key(&secret_key)?;
+        let signature = conformance_tests::index::sign(&index, &secret_key);
+        std::fs::write(
+            temp_dir_path.join(conformance_tests::index::SIGNATURE_FILE_NAME),
+            signature,
+        )
+        .context("failed to write test index signature")?;
+        let public_key = conformance_tests::index::public_key(&secret_key);
+        println!(
+            "Signed test index with public key {}",
+            hex::encode(public_key)
+        );
+    }
+
     // Create the tarball from the temporary directory
     let tar_gz = File::create(output_tar)?;
     let enc = flate2::write::GzEncoder::new(tar_gz, flate2::Compression::default());
@@ -123,24 +179,53 @@ fn package_into(dir_path: impl AsRef<Path>) -> anyhow::Result<()> {
         std::fs::create_dir_all(&test_archive).context("failed to create component directory")?;
 
         // Check that the configuration file can be parsed:
-        let config_file_path = test_path.join("test.json5");
+        let config_file_path =
+            conformance_tests::config::confine(&test_path, "test.json5".as_ref())?;
         let _ = conformance_tests::config::parse_from_file(&config_file_path)
             .context("failed to parse test manifest")?;
 
         // Copy the configuration and manifest files to the temporary directory
         std::fs::copy(config_file_path, test_archive.join("test.json5"))
             .context("failed to copy test manifest to temp directory")?;
-        let mut manifest = std::fs::read_to_string(test_path.join("spin.toml"))
-            .context("failed to read spin manifest")?;
+        let manifest_path = conformance_tests::config::confine(&test_path, "spin.toml".as_ref())?;
+        let mut manifest =
+            std::fs::read_to_string(manifest_path).context("failed to read spin manifest")?;
         substitute_source(&mut manifest, &components, &test_archive)
             .context("failed to substitute component template for actual component binary")?;
         std::fs::write(test_archive.join("spin.toml"), manifest.as_bytes())
             .context("failed to copy spin manifest to temp directory")?;
+
+        // Copy any other assets the test references (e.g. expected response bodies)
+        for entry in std::fs::read_dir(&test_path).context("failed to read test directory")? {
+            let entry = entry.context("failed to read test directory entry")?;
+            let file_name = entry.file_name();
+            if file_name == "test.json5" || file_name == "spin.toml" {
+                continue;
+            }
+            copy_all(&test_path, &entry.path(), &test_archive.join(file_name))
+                .context("failed to copy test asset to temp directory")?;
+        }
     }
 
     Ok(())
 }
 
+/// Copy a file or a directory and all of its contents, all of which must be inside of `test_dir`
+fn copy_all(test_dir: &Path, from: &Path, to: &Path) -> anyhow::Result<()> {
+    // Refuse to pull in anything a symlink points to outside of the test
+    conformance_tests::config::confine(test_dir, from)?;
+    if from.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_all(test_dir, &entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        std::fs::copy(from, to).with_context(|| format!("failed to copy {from:?}"))?;
+    }
+    Ok(())
+}
+
 fn find_wasm_file(dir: &Path) -> anyhow::Result<Option<PathBuf>> {
     for entry in std::fs::read_dir(dir).context("failed to read directory")? {
         let entry = entry.context("failed to read entry")?;
@@ -156,7 +241,7 @@ fn find_wasm_file(dir: &Path) -> anyhow::Result<Option<PathBuf>> {
 
 /// Substitute templated "source" in the spin.toml manifest
 ///
-/// Also writes the component binary into the test archive.
+/// Also writes the binary of every referenced component into the test archive.
 fn substitute_source(
     manifest: &mut String,
     components: &HashMap<String, PathBuf>,
@@ -178,9 +263,11 @@ fn substitute_source(
                 let path = components
                     .get(template_value)
                     .with_context(|| format!("'{template_value}' is not a known component"))?;
-                let component_file = "component.wasm";
-                std::fs::copy(path, test_archive.join(component_file))?;
-                manifest.replace_range(full.range(), component_file);
+                // Each component gets its own file so that manifests referencing
+                // several components don't overwrite each other's binaries
+                let component_file = format!("{template_value}.wasm");
+                std::fs::copy(path, test_archive.join(&component_file))?;
+                manifest.replace_range(full.range(), &component_file);
                 // Restart the search after a substitution
                 continue 'outer;
             }
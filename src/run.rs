@@ -0,0 +1,373 @@
+//! Running the conformance tests against a runtime that is started as a child process
+
+use anyhow::Context as _;
+use conformance_tests::{
+    config::{self, CommandOutput, Invocation, Variables},
+    libtest_mimic,
+    report::ReportFormat,
+    Test, TestFailure, MANIFEST_FILE_NAME,
+};
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write as _},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+use test_environment::{io::OutputStream, Runtime, TestEnvironment};
+
+/// How long to wait for the runtime to start accepting connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a runtime that doesn't listen for HTTP requests must keep running to be considered started
+const STARTUP_GRACE_PERIOD: Duration = Duration::from_secs(1);
+/// How long to wait for a command app to exit
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Run the tests found in `tests_dir` against the runtime started by `runtime_command`
+///
+/// `test_args` are the arguments passed on to the test harness (e.g. a filter or `--list`) as well
+/// as `--junit-report <path>` and `--json-report <path>` to write reports of the run.
+///
+/// The runtime command may contain the following placeholders:
+/// - `{addr}`: the address the runtime is expected to listen on (e.g. `127.0.0.1:3000`)
+/// - `{manifest}`: the path to the test's manifest
+pub fn run(
+    tests_dir: &str,
+    test_args: Vec<String>,
+    runtime_command: Vec<String>,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !runtime_command.is_empty(),
+        "no runtime command was provided"
+    );
+    let mut config = conformance_tests::Config::new("local");
+    // Report options are handled here while all others are passed on to the test harness
+    let mut harness_args = vec![String::from("test-runner run")];
+    let mut test_args = test_args.into_iter();
+    while let Some(arg) = test_args.next() {
+        let format = match arg.as_str() {
+            "--junit-report" => ReportFormat::JUnit,
+            "--json-report" => ReportFormat::Json,
+            _ => {
+                harness_args.push(arg);
+                continue;
+            }
+        };
+        let path = test_args
+            .next()
+            .with_context(|| format!("{arg} requires a path"))?;
+        config = config.report(format, path);
+    }
+    let config = config.args(libtest_mimic::Arguments::from_iter(harness_args));
+    let conclusion = conformance_tests::run_tests_from(tests_dir, config, move |test| {
+        run_test(test, runtime_command.clone())
+    })?;
+    conclusion.exit_if_failed();
+    Ok(())
+}
+
+/// Run a single test against the runtime
+fn run_test(test: Test, runtime_command: Vec<String>) -> anyhow::Result<()> {
+    let mut env = test.prepare_environment()?;
+    if let Some(expected) = &test.config.startup_failure {
+        let mut runtime = ProcessRuntime::spawn(&mut env, &runtime_command)?;
+        return expected.assert(&mut runtime).map_err(|error| {
+            TestFailure::new(error)
+                .log("runtime", runtime.output())
+                .service_logs(env.services_mut())
+                .into()
+        });
+    }
+    let invocations = &test.config.invocations;
+    // Command apps are run once per invocation rather than by a long running runtime, and apps
+    // that are only triggered by Redis messages never listen for HTTP requests
+    let runtime = if invocations.iter().any(|i| matches!(i, Invocation::Http(_))) {
+        Some(
+            ProcessRuntime::start(&mut env, &runtime_command)
+                .map_err(|error| with_service_logs(TestFailure::new(error), &mut env))?,
+        )
+    } else if invocations
+        .iter()
+        .any(|i| matches!(i, Invocation::Redis(_)))
+    {
+        Some(
+            ProcessRuntime::start_without_listener(&mut env, &runtime_command)
+                .map_err(|error| with_service_logs(TestFailure::new(error), &mut env))?,
+        )
+    } else {
+        None
+    };
+    let mut env = env.start_runtime(runtime)?;
+    let postconditions = test
+        .config
+        .watch_postconditions(&mut env)
+        .map_err(|error| with_logs(TestFailure::new(error), &mut env))?;
+
+    let mut variables = Variables::new();
+    for (index, invocation) in test.config.invocations.into_iter().enumerate() {
+        let result = match invocation {
+            Invocation::Http(mut invocation) => {
+                let addr = env
+                    .runtime_mut()
+                    .as_ref()
+                    .expect("runtime should be started for HTTP invocations")
+                    .addr;
+                invocation
+                    .request
+                    .substitute_from_env(&mut env)
+                    .and_then(|_| {
+                        invocation.run_with_variables(&mut variables, |request| {
+                            request.send(&addr.ip().to_string(), addr.port())
+                        })
+                    })
+                    .map(drop)
+            }
+            Invocation::Redis(invocation) => invocation.run(&mut env),
+            Invocation::Command(invocation) => invocation
+                .run(|command| run_command(&env, &runtime_command, command))
+                .map(drop),
+        };
+        if let Err(error) = result {
+            return Err(with_logs(
+                TestFailure::new(error).invocation(index),
+                &mut env,
+            ));
+        }
+    }
+    postconditions
+        .check(&mut env)
+        .map_err(|error| with_logs(TestFailure::new(error), &mut env))
+}
+
+/// Attach the logs of the runtime and the services to a test failure
+fn with_logs(
+    mut failure: TestFailure,
+    env: &mut TestEnvironment<Option<ProcessRuntime>>,
+) -> anyhow::Error {
+    if let Some(runtime) = env.runtime_mut() {
+        failure = failure.log("runtime", runtime.output());
+    }
+    with_service_logs(failure, env)
+}
+
+/// Attach the logs of the services to a test failure
+///
+/// Failures to start the runtime already include its output.
+fn with_service_logs<R>(failure: TestFailure, env: &mut TestEnvironment<R>) -> anyhow::Error {
+    failure.service_logs(env.services_mut()).into()
+}
+
+/// Run a command app by running the runtime with the invocation's arguments appended
+fn run_command<R>(
+    env: &TestEnvironment<R>,
+    runtime_command: &[String],
+    command: &config::Command,
+) -> anyhow::Result<CommandOutput> {
+    let mut child = runtime_process(env, runtime_command, free_addr()?, &command.env)?
+        .args(&command.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to spawn runtime '{}'", runtime_command[0]))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = command.stdin.clone().unwrap_or_default();
+    // Dropping stdin once it's written closes it so the app sees the end of its input
+    std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_in_background(child.stderr.take().unwrap());
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > COMMAND_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("command did not exit within {COMMAND_TIMEOUT:?}");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    Ok(CommandOutput {
+        exit_code: status
+            .code()
+            .with_context(|| format!("runtime was terminated with {status}"))?,
+        stdout: stdout.join().expect("reader thread panicked")?,
+        stderr: stderr.join().expect("reader thread panicked")?,
+    })
+}
+
+/// Read a stream to its end on another thread
+fn read_in_background(
+    mut stream: impl Read + Send + 'static,
+) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output)?;
+        Ok(output)
+    })
+}
+
+/// A runtime running as a child process
+struct ProcessRuntime {
+    child: std::process::Child,
+    stdout: OutputStream,
+    stderr: OutputStream,
+    addr: SocketAddr,
+}
+
+impl ProcessRuntime {
+    /// Start the runtime and wait for it to accept connections
+    fn start<R>(env: &mut TestEnvironment<R>, command: &[String]) -> anyhow::Result<Self> {
+        let mut runtime = Self::spawn(env, command)?;
+        runtime.wait_until_listening()?;
+        Ok(runtime)
+    }
+
+    /// Start a runtime which doesn't listen on its address and check it keeps running
+    ///
+    /// There is no telling when such a runtime is ready, so its invocations must retry until
+    /// their effects are observed (e.g. until the runtime has subscribed to a Redis channel).
+    fn start_without_listener<R>(
+        env: &mut TestEnvironment<R>,
+        command: &[String],
+    ) -> anyhow::Result<Self> {
+        let mut runtime = Self::spawn(env, command)?;
+        let start = Instant::now();
+        while start.elapsed() < STARTUP_GRACE_PERIOD {
+            runtime.error()?;
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        runtime.error()?;
+        Ok(runtime)
+    }
+
+    /// Start the runtime without waiting for it
+    fn spawn<R>(env: &mut TestEnvironment<R>, command: &[String]) -> anyhow::Result<Self> {
+        let addr = free_addr()?;
+        let mut child = runtime_process(env, command, addr, &BTreeMap::new())?
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to spawn runtime '{}'", command[0]))?;
+        let stdout = OutputStream::new(child.stdout.take().unwrap());
+        let stderr = OutputStream::new(child.stderr.take().unwrap());
+        Ok(Self {
+            child,
+            stdout,
+            stderr,
+            addr,
+        })
+    }
+
+    /// Block until the runtime accepts connections on its address
+    fn wait_until_listening(&mut self) -> anyhow::Result<()> {
+        let start = Instant::now();
+        loop {
+            self.error()?;
+            if TcpStream::connect(self.addr).is_ok() {
+                return Ok(());
+            }
+            if start.elapsed() > STARTUP_TIMEOUT {
+                let addr = self.addr;
+                anyhow::bail!(
+                    "runtime did not start listening on {addr} within {STARTUP_TIMEOUT:?}\n{}",
+                    self.output()
+                );
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// The output of the runtime so far
+    fn output(&mut self) -> String {
+        format!(
+            "stdout:\n{}\nstderr:\n{}",
+            String::from_utf8_lossy(self.stdout.output()),
+            String::from_utf8_lossy(self.stderr.output())
+        )
+    }
+}
+
+impl Runtime for ProcessRuntime {
+    fn error(&mut self) -> anyhow::Result<()> {
+        if let Some(status) = self.child.try_wait()? {
+            anyhow::bail!("runtime exited early with {status}\n{}", self.output());
+        }
+        Ok(())
+    }
+
+    fn startup_failure(&mut self) -> Option<String> {
+        let start = Instant::now();
+        while start.elapsed() < STARTUP_TIMEOUT {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                // Exiting successfully without listening isn't refusing to start
+                if status.success() {
+                    return None;
+                }
+                // Make sure everything the runtime wrote before exiting is captured
+                self.stdout.output_until_closed(Duration::from_secs(1));
+                self.stderr.output_until_closed(Duration::from_secs(1));
+                return Some(format!("runtime exited with {status}\n{}", self.output()));
+            }
+            if TcpStream::connect(self.addr).is_ok() {
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        None
+    }
+}
+
+impl Drop for ProcessRuntime {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The runtime command with its placeholders substituted, run in the test environment
+///
+/// An `{env}` argument is expanded to a `--env NAME=VALUE` pair for every variable in `app_env`.
+fn runtime_process<R>(
+    env: &TestEnvironment<R>,
+    command: &[String],
+    addr: SocketAddr,
+    app_env: &BTreeMap<String, String>,
+) -> anyhow::Result<Command> {
+    let manifest = env.path().join(MANIFEST_FILE_NAME);
+    let args = command
+        .iter()
+        .flat_map(|arg| match arg.as_str() {
+            "{env}" => app_env
+                .iter()
+                .flat_map(|(name, value)| ["--env".to_owned(), format!("{name}={value}")])
+                .collect(),
+            _ => vec![arg
+                .replace("{addr}", &addr.to_string())
+                .replace("{manifest}", &manifest.display().to_string())],
+        })
+        .collect::<Vec<_>>();
+    // Relative paths to the runtime are resolved against the current directory and
+    // not the test environment the runtime is run in
+    let program = Path::new(&args[0]);
+    let program = if program.components().count() > 1 {
+        std::path::absolute(program)?
+    } else {
+        program.to_owned()
+    };
+    let mut process = Command::new(program);
+    process
+        .args(&args[1..])
+        .current_dir(env.path())
+        .envs(env.env_vars());
+    Ok(process)
+}
+
+/// Find a free address on the loopback interface
+fn free_addr() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .context("failed to find a free port for the runtime")?;
+    Ok(listener.local_addr()?)
+}